@@ -0,0 +1,107 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Compare hashing a large match by copying it out with `extract` against
+//! streaming it through `checksum`. Run with `cargo bench --bench checksum`.
+
+use rawr::lang::{Dialect, MatchType, Matcher, SupportedLanguage};
+use rawr::scan::{checksum, extract};
+use sha2::{Digest, Sha256};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tree_sitter::{Parser, QueryCursor};
+
+/// Counts bytes allocated, to compare the memory used by each approach.
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const ITERATIONS: u32 = 200;
+
+/// Time and total allocations for running `f` repeatedly.
+fn measure(mut f: impl FnMut()) -> (Duration, usize) {
+    let allocated = ALLOCATED.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    (
+        start.elapsed() / ITERATIONS,
+        (ALLOCATED.load(Ordering::Relaxed) - allocated) / ITERATIONS as usize,
+    )
+}
+
+fn main() -> anyhow::Result<()> {
+    // A single function with a body of about a megabyte.
+    let body = "    let x = 1 + 2 * 3;\n".repeat(50_000);
+    let source = format!("fn large() {{\n{body}}}\n");
+    let source_bytes = source.as_bytes();
+
+    let contents = || {
+        MatchType::Joined(
+            "\n".to_string(),
+            vec![
+                MatchType::Capture("name".to_string()),
+                MatchType::Capture("body".to_string()),
+            ],
+        )
+    };
+    let matcher = Matcher {
+        kind: "function".to_string(),
+        query: "((function_item name: (_) @name body: (_) @body) @outer)".to_string(),
+        identifier: MatchType::Capture("name".to_string()),
+        contents: contents(),
+        excludes: None,
+        notes: None,
+    };
+    let dialect = Dialect::new(SupportedLanguage::Rust, vec![matcher])?;
+    let compiled = &dialect.matchers[0];
+
+    let mut parser = Parser::new();
+    parser.set_language(dialect.grammar)?;
+    let tree = parser.parse(source_bytes, None).expect("Parse source");
+    let mut cursor = QueryCursor::new();
+    let matched = cursor
+        .matches(&compiled.query, tree.root_node(), source_bytes)
+        .next()
+        .expect("Match function");
+
+    let match_type = contents();
+    let (extract_time, extract_bytes) = measure(|| {
+        let bytes = extract(&match_type, compiled, &matched, source_bytes, "large.rs").unwrap();
+        black_box(Sha256::digest(&bytes));
+    });
+    let (checksum_time, checksum_bytes) = measure(|| {
+        let mut digest = Sha256::new();
+        checksum(
+            &match_type,
+            compiled,
+            &matched,
+            source_bytes,
+            "large.rs",
+            &mut |bytes| digest.update(bytes),
+        )
+        .unwrap();
+        black_box(digest.finalize());
+    });
+
+    println!("Hashing a {} byte match:", source_bytes.len());
+    println!("\textract:  {extract_time:?}, {extract_bytes} bytes allocated");
+    println!("\tchecksum: {checksum_time:?}, {checksum_bytes} bytes allocated");
+    Ok(())
+}
@@ -0,0 +1,58 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Dump stored matches for use in other tools. Rows are written as they are
+//! read, so large databases aren't held in memory.
+
+use anyhow::{bail, Context};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use rawr::db::{ExportFilter, ExportFormat};
+
+const USAGE: &str = "Usage: export [OPTIONS] DATABASE
+    --format jsonl|csv  Output format, JSON lines by default
+    --codebase NAME     Only export matches from this codebase
+    --revision REV      Only export matches at this revision
+    --kind KIND         Only export matches of this kind, such as function";
+
+fn main() -> anyhow::Result<()> {
+    let mut format = ExportFormat::JsonLines;
+    let mut filter = ExportFilter::default();
+    let mut positional = Vec::<String>::new();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => {
+                format = match args
+                    .next()
+                    .context("--format requires jsonl or csv")?
+                    .as_str()
+                {
+                    "jsonl" => ExportFormat::JsonLines,
+                    "csv" => ExportFormat::Csv,
+                    format => bail!("Unknown format {format}, expected jsonl or csv"),
+                }
+            }
+            "--codebase" => {
+                filter.codebase = Some(args.next().context("--codebase requires a name")?)
+            }
+            "--revision" => {
+                filter.revision = Some(args.next().context("--revision requires a revision")?)
+            }
+            "--kind" => filter.kind = Some(args.next().context("--kind requires a kind")?),
+            _ => positional.push(arg),
+        }
+    }
+
+    let [database] = positional.as_slice() else {
+        bail!(USAGE);
+    };
+
+    let conn = rawr::db::connect_ro(Path::new(database))?;
+    let mut out = BufWriter::new(std::io::stdout().lock());
+    rawr::db::export(&conn, &filter, format, &mut out)?;
+    out.flush()?;
+
+    Ok(())
+}
@@ -6,231 +6,272 @@
 #![allow(dead_code)]
 #![allow(unused_imports)]
 
-use anyhow::bail;
-use sha2::{Digest, Sha256};
-use std::borrow::Cow;
-use std::collections::HashMap;
-use std::io::Read;
+use anyhow::{bail, Context};
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
+use tracing_subscriber::EnvFilter;
 
-use rawr::lang::{MatchType, Matcher, SupportedLanguage};
-use rawr::Interesting;
-use tree_sitter::{Language, Parser, Query, QueryCursor, QueryMatch};
+use rawr::lang::{Dialect, SupportedLanguage};
+use rawr::scan::{resume_point, revision_range, Scanner, DEFAULT_MAX_FILE_SIZE};
+use rawr::{duplicate_ranges, root_relative_path, Interesting};
 use tree_sitter_bash;
 use tree_sitter_c;
 use tree_sitter_cpp;
 use tree_sitter_rust;
 
-fn main() -> anyhow::Result<()> {
-    // Build matchers for supported languages
-    let mut language_matchers = HashMap::<SupportedLanguage, Vec<Matcher>>::new();
-    language_matchers.insert(SupportedLanguage::Rust, rawr::lang::matchers_rust());
-    language_matchers.insert(SupportedLanguage::Bash, rawr::lang::matchers_bash());
-
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() < 2 {
-        bail!("File names must be specified");
-    }
+const USAGE: &str = "Usage: interesting-items [OPTIONS] FILE...
+    --database PATH          Store matches in a SQLite database
+    --revision REV|FROM..TO  Read files from git revisions, not the working directory
+    --since REV              Only scan revisions after REV, up to --revision or HEAD.
+                             With --database, defaults to the newest stored ancestor
+    --max-size BYTES         Skip larger files
+    --encoding LABEL         Read source files in an encoding such as latin1, not UTF-8
+    --overlaps               Report matches of different kinds with identical ranges
+    --whole-file             Also match entire files, identified by their canonical path
+    --whole-file-id TEMPLATE Identify whole files by a template, such as ${file_name}
+    --disable KIND           Skip matchers of this kind, such as macro. Repeatable
+    --cache                  Reuse matches for files scanned by earlier runs, stored
+                             in the --database
+    --progress               Report each revision as it is scanned
+    --dry-run                Report what would be stored without writing";
 
-    // Process known filetypes
-    args.into_iter().skip(1).for_each(|arg| {
-        let path = Path::new(&arg);
-
-        let Some(file_extension) = path.extension() else {
-            return;
-        };
+/// Codebase name recorded for matches.
+const CODEBASE: &str = "(self)";
 
-        let lang = match file_extension.to_str() {
-            Some("rs") => SupportedLanguage::Rust,
-            Some("sh") => SupportedLanguage::Bash,
-            _ => return,
-        };
-
-        let Ok(matches) = find_matches_in_file(path, lang) else {
-            return;
-        };
+/// Identifier template for `--whole-file`.
+const WHOLE_FILE_IDENTIFIER: &str = "${file_name}";
 
-        println!("Found {} matches in file.", matches.len());
-    });
+/// Matches printed by `--dry-run`.
+const DRY_RUN_SAMPLE: usize = 10;
 
-    Ok(())
-}
+fn main() -> anyhow::Result<()> {
+    // Filter logs with RUST_LOG, such as `rawr[file{path=src/lib.rs}]=debug`.
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("warn")),
+        )
+        .with_writer(std::io::stderr)
+        .init();
 
-fn find_matches_in_file(path: &Path, lang: SupportedLanguage) -> anyhow::Result<Vec<Interesting>> {
-    println!("Searching for matches in {}", path.display());
+    // Build dialects for supported languages
+    let mut dialects = HashMap::<SupportedLanguage, Dialect>::new();
+    dialects.insert(SupportedLanguage::Rust, Dialect::rust()?);
+    dialects.insert(SupportedLanguage::Bash, Dialect::bash()?);
 
-    let (language, matchers) = match lang {
-        SupportedLanguage::Rust => (tree_sitter_rust::language(), rawr::lang::matchers_rust()),
-        SupportedLanguage::Bash => (tree_sitter_bash::language(), rawr::lang::matchers_bash()),
-        SupportedLanguage::C => todo!(),
-        SupportedLanguage::Cpp => todo!(),
-    };
+    let mut database: Option<String> = None;
+    let mut revision: Option<String> = None;
+    let mut since: Option<String> = None;
+    let mut max_size = DEFAULT_MAX_FILE_SIZE;
+    let mut progress = false;
+    let mut cache = false;
+    let mut dry_run = false;
+    let mut overlaps = false;
+    let mut disabled = Vec::<String>::new();
+    let mut whole_file: Option<String> = None;
+    let mut encoding: Option<String> = None;
+    let mut files = Vec::<String>::new();
 
-    // Open and read file
-    let mut file = std::fs::File::open(path)?;
-    let mut source_bytes = Vec::new();
-    file.read_to_end(&mut source_bytes)?;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--database" => database = Some(args.next().context("--database requires a path")?),
+            "--revision" => revision = Some(args.next().context("--revision requires a treeish")?),
+            "--since" => since = Some(args.next().context("--since requires a treeish")?),
+            "--progress" => progress = true,
+            "--cache" => cache = true,
+            "--dry-run" => dry_run = true,
+            "--overlaps" => overlaps = true,
+            "--whole-file" => whole_file = Some(WHOLE_FILE_IDENTIFIER.to_string()),
+            "--whole-file-id" => {
+                whole_file = Some(args.next().context("--whole-file-id requires a template")?)
+            }
+            "--encoding" => encoding = Some(args.next().context("--encoding requires a label")?),
+            "--disable" => disabled.push(args.next().context("--disable requires a kind")?),
+            "--max-size" => {
+                max_size = args
+                    .next()
+                    .context("--max-size requires a number of bytes")?
+                    .parse()
+                    .context("--max-size must be a number of bytes")?
+            }
+            _ => files.push(arg),
+        }
+    }
 
-    // Parse file
-    let mut parser = Parser::new();
-    parser
-        .set_language(language)
-        .expect("Create language parser");
+    if files.is_empty() {
+        bail!(USAGE);
+    }
 
-    let tree = parser
-        .parse(&source_bytes.as_slice(), None)
-        .expect("Parse file");
+    if let Some(identifier) = &whole_file {
+        dialects = dialects
+            .into_iter()
+            .map(|(language, dialect)| Ok((language, dialect.with_whole_file(identifier)?)))
+            .collect::<anyhow::Result<_>>()?;
+    }
 
-    // Find matches
-    let mut interesting_matches = Vec::<Interesting>::new();
-    for matcher in &matchers {
-        // Find matches and extract information
-        let query = match Query::new(language, matcher.query.as_str()) {
-            Ok(query) => query,
-            Err(e) => {
-                eprintln!("Skipping unparseable query {}", matcher.query);
-                eprintln!("{}", e);
-                continue;
-            }
-        };
+    if let Some(label) = &encoding {
+        let encoding = encoding_rs::Encoding::for_label(label.as_bytes())
+            .with_context(|| format!("Unknown encoding {label}"))?;
+        dialects = dialects
+            .into_iter()
+            .map(|(language, dialect)| (language, dialect.with_encoding(Some(encoding))))
+            .collect();
+    }
 
-        let mut cursor = QueryCursor::new();
-        let matches = cursor.matches(&query, tree.root_node(), source_bytes.as_slice());
-        let processed = matches.filter_map(|matched| {
-            process_match(
-                &"(self)".to_string(),
-                &"(unversioned)".to_string(),
-                &path,
-                &language,
-                &source_bytes,
-                &matcher,
-                &matched,
-            )
-        });
-        interesting_matches.extend(processed);
+    for kind in &disabled {
+        let removed: usize = dialects
+            .values_mut()
+            .map(|dialect| dialect.disable(kind))
+            .sum();
+        if removed == 0 {
+            bail!("--disable {kind} doesn't match any matcher kind");
+        }
     }
 
-    // These should probably be concatenated for efficiency, but settle for repeated searches. O(matches * files)
-    // todo!("Open file, parse, and build list of all matches");
-    Ok(interesting_matches)
-}
+    // --since scans up to HEAD unless told otherwise.
+    if since.is_some() && revision.is_none() {
+        revision = Some("HEAD".to_string());
+    }
 
-fn process_match(
-    codebase: &String,
-    revision: &String,
-    path: &Path,
-    language: &Language,
-    source_bytes: &[u8],
-    matcher: &Matcher,
-    matched: &QueryMatch,
-) -> Option<Interesting> {
-    let Some(root_match) = matched.captures.get(0) else {
-        return None;
+    // Paths are stored relative to the repository root, or to the current
+    // directory outside of a repository, wherever the scan is run from.
+    let cwd = std::env::current_dir()?.canonicalize()?;
+    let repo = gix::discover(".");
+    let root = match repo.as_ref().ok().and_then(|repo| repo.work_dir()) {
+        Some(work_dir) => work_dir.canonicalize()?,
+        None => cwd.clone(),
     };
+    let files = files
+        .iter()
+        .map(|file| root_relative_path(&root, &cwd, Path::new(file)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
 
-    let file_path = path.to_string_lossy();
+    // Revisions are written as they are scanned, rather than all at the end.
+    let mut writer = match (&database, dry_run) {
+        (Some(database), false) => Some(rawr::db::Writer::spawn(Path::new(database))?),
+        _ => None,
+    };
 
-    // Identifier: Extract a string
-    // FIXME Need to hand back a string, which could possibly be a constant value like the filename or empty string.
-    let identifier_text = match &matcher.identifier {
-        MatchType::Match => {
-            let range = root_match.node.start_byte()..root_match.node.end_byte();
-            let text = String::from_utf8_lossy(&source_bytes[range]);
-            Some(text)
-        }
-        MatchType::Kind(_kind, _index) => {
-            // Iterate over children to find one of the right kind.
-            todo!("Build query for subtype")
+    let mut scanner = Scanner::new(CODEBASE, dialects);
+    scanner.max_size = max_size;
+    if cache {
+        let Some(database) = &database else {
+            bail!("--cache requires --database");
+        };
+        // A dry run doesn't fill the cache, as that would write.
+        if !dry_run {
+            scanner.cache = Some(rawr::db::connect_rw(Path::new(database))?);
         }
-        MatchType::Named(child_name) => {
-            let child = root_match.node.child_by_field_name(child_name);
-            if let Some(node) = child {
-                let range = node.start_byte()..node.end_byte();
-                let text = String::from_utf8_lossy(&source_bytes[range]);
-                Some(text)
-            } else {
-                None
+    }
+    let mut interesting_matches = Vec::<Interesting>::new();
+    // Without a revision, read from the working directory instead.
+    match &revision {
+        Some(revision) => {
+            let repo = repo?;
+            // Revisions to read from, oldest first.
+            let revisions = match (revision.split_once(".."), &since, &database) {
+                (Some(_), Some(_), _) => bail!("--since can't be combined with a revision range"),
+                (Some((from, to)), None, _) => revision_range(&repo, from, to)?,
+                (None, Some(since), _) => revision_range(&repo, since, revision)?,
+                // Continue after the newest revision already in the database.
+                (None, None, Some(database)) => {
+                    let target = repo.rev_parse_single(revision.as_str())?.detach();
+                    let scanned = scanned_revisions(database, dry_run)?;
+                    match resume_point(&repo, target, &scanned)? {
+                        Some(resume) => {
+                            println!("Continuing after {resume}.");
+                            revision_range(&repo, &resume.to_string(), revision)?
+                        }
+                        None => vec![target],
+                    }
+                }
+                (None, None, None) => vec![repo.rev_parse_single(revision.as_str())?.detach()],
+            };
+            for (index, id) in revisions.iter().enumerate() {
+                if progress {
+                    eprintln!("Revision {}/{}: {}", index + 1, revisions.len(), id);
+                }
+                let found = scanner.scan_revision(&repo, *id, &files)?;
+                store(&mut writer, &found)?;
+                interesting_matches.extend(found);
             }
         }
-        MatchType::SubQuery(_match_id, query_string) => {
-            let _query = Query::new(*language, query_string).expect("Parse identifier query");
-            let mut _cursor = QueryCursor::new();
-            todo!("Return results of sub-query")
+        None => {
+            interesting_matches = scanner.scan_worktree(&root, &files);
+            store(&mut writer, &interesting_matches)?;
         }
-        MatchType::String(text) => {
-            Some(Cow::from(text.replace("${file_name}", file_path.as_ref())))
-        }
-    };
+    }
 
-    let Some(identifier) = identifier_text else {
-        println!("Failed to match identifier");
-        return None;
-    };
+    println!("{}", scanner.stats);
 
-    // TODO Get matched bytes, then convert to string for identifiers?
-    // TODO Try to capture start and length
-    // DESIGN Rewrite all arms to fill a buf.
-    // Contents
-    let mut buf = Vec::<u8>::new();
-    let body_bytes = match &matcher.contents {
-        MatchType::Match => {
-            let range = root_match.node.start_byte()..root_match.node.end_byte();
-            let bytes = &source_bytes[range];
-            Some(bytes)
-        }
-        MatchType::Kind(_index, _kind) => {
-            // Iterate over all children for anything matching type, and pick index.
-            todo!("Build query for subtype")
-        }
-        MatchType::Named(child_name) => {
-            let child_node = root_match.node.child_by_field_name(child_name);
-            if let Some(node) = child_node {
-                let range = node.start_byte()..node.end_byte();
-                let bytes = &source_bytes[range];
-                Some(bytes)
-            } else {
-                None
-            }
+    if overlaps {
+        for (first, second) in duplicate_ranges(&interesting_matches) {
+            println!(
+                "Overlap in {}@{}: {} {} and {} {} share the same range",
+                first.path,
+                first.revision,
+                first.kind,
+                first.identifier,
+                second.kind,
+                second.identifier
+            );
         }
-        MatchType::SubQuery(_match_id, query_string) => {
-            let _query = Query::new(*language, query_string.as_str()).expect("Parse matcher query");
-            let mut _cursor = QueryCursor::new();
-            todo!("Return results of sub-query")
+    }
+
+    if dry_run {
+        for item in interesting_matches.iter().take(DRY_RUN_SAMPLE) {
+            println!(
+                "Would store {} {} in {}@{}",
+                item.kind, item.identifier, item.path, item.revision
+            );
         }
-        MatchType::String(text) => {
-            let replaced = text.replace("${file_name}", file_path.as_ref());
-            let bytes = replaced.as_bytes();
-            buf.copy_from_slice(bytes);
-            Some(buf.as_slice())
+        if interesting_matches.len() > DRY_RUN_SAMPLE {
+            println!(
+                "... and {} more.",
+                interesting_matches.len() - DRY_RUN_SAMPLE
+            );
         }
-    };
+        println!("Dry run. Nothing was written.");
+        return Ok(());
+    }
+
+    if let Some(writer) = writer {
+        let summary = writer.finish()?;
+        println!(
+            "Inserted {}, skipped {} known, {} conflicting.",
+            summary.inserted, summary.skipped, summary.conflicts
+        );
+    }
+
+    Ok(())
+}
 
-    let Some(contents) = body_bytes else {
-        println!("Failed to match contents");
-        return None;
+/// Queue matches for the database writer, if any. A writer that has stopped is
+/// finished, to report the error that stopped it.
+fn store(writer: &mut Option<rawr::db::Writer>, found: &[Interesting]) -> anyhow::Result<()> {
+    let Some(active) = writer.as_ref() else {
+        return Ok(());
     };
+    if active.send(found.to_vec()).is_ok() {
+        return Ok(());
+    }
+
+    let stopped = writer.take().expect("Writer was just used");
+    stopped.finish()?;
+    bail!("Database writer stopped unexpectedly")
+}
 
-    // Salted hash of contents, in case of sensitive data.
-    let hash_algorithm = "sha256".to_string();
-    let mut hasher = Sha256::new();
-
-    let salt: u64 = rand::random();
-    hasher.update(salt.to_be_bytes());
-    hasher.update(contents);
-
-    let hash = format!("{:02x}", Sha256::digest(contents));
-
-    Some(Interesting {
-        codebase: codebase.to_string(),
-        revision: revision.to_string(),
-        path: file_path.to_string(),
-        start_byte: None,
-        length: None,
-        kind: matcher.kind.to_string(),
-        identifier: identifier.to_string(),
-        hash_algorithm,
-        salt,
-        hash,
-        notes: None,
-    })
+/// Revisions already stored in the database. A dry run must not create or
+/// migrate the database, so a missing database has no revisions.
+fn scanned_revisions(database: &str, dry_run: bool) -> anyhow::Result<HashSet<String>> {
+    let path = Path::new(database);
+    let conn = if dry_run {
+        if !path.exists() {
+            return Ok(HashSet::new());
+        }
+        rawr::db::connect_ro(path)?
+    } else {
+        rawr::db::connect_rw(path)?
+    };
+    rawr::db::scanned_revisions(&conn, CODEBASE)
 }
@@ -0,0 +1,90 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Run an ad-hoc Tree-Sitter query against a file and print each match's
+//! captures, for developing new matchers.
+
+use anyhow::{bail, Context};
+use std::path::Path;
+
+use rawr::debug::describe_matches;
+use rawr::lang::SupportedLanguage;
+use rawr::root_relative_path;
+use rawr::scan::read_source;
+use tree_sitter::{Parser, Query};
+
+const USAGE: &str = "Usage: query [OPTIONS] QUERY FILE
+    --revision REV  Read the file from a git revision, not the working directory";
+
+fn main() -> anyhow::Result<()> {
+    let mut revision: Option<String> = None;
+    let mut positional = Vec::<String>::new();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--revision" => revision = Some(args.next().context("--revision requires a treeish")?),
+            _ => positional.push(arg),
+        }
+    }
+
+    let [query_string, file] = positional.as_slice() else {
+        bail!(USAGE);
+    };
+    let path = Path::new(file);
+
+    let source_bytes = match &revision {
+        Some(revision) => read_revision(revision, path)?,
+        None => std::fs::read(path).with_context(|| format!("Read {}", path.display()))?,
+    };
+
+    let language = SupportedLanguage::detect(path, &source_bytes)
+        .with_context(|| format!("Unrecognized language for {}", path.display()))?;
+    let grammar = language.compatible_grammar()?;
+
+    let mut parser = Parser::new();
+    parser
+        .set_language(grammar)
+        .with_context(|| format!("Create {language:?} parser"))?;
+    let tree = parser
+        .parse(&source_bytes, None)
+        .context("Parser did not produce a tree")?;
+
+    let query = Query::new(grammar, query_string).context("Compile query")?;
+    let described = describe_matches(&query, &source_bytes, &tree);
+    for (count, matched) in described.iter().enumerate() {
+        println!("Match {} (pattern {})", count + 1, matched.pattern_index);
+        for capture in &matched.captures {
+            println!(
+                "\t@{} {} {:?}: {:?}",
+                capture.name, capture.kind, capture.range, capture.text
+            );
+            for detail in &capture.details {
+                println!("\t\t{detail}");
+            }
+        }
+    }
+    println!("{} matches.", described.len());
+
+    Ok(())
+}
+
+/// Read a file's contents at a revision of the enclosing repository.
+fn read_revision(revision: &str, path: &Path) -> anyhow::Result<Vec<u8>> {
+    let repo = gix::discover(".")?;
+    let tree = repo.rev_parse_single(revision)?.object()?.peel_to_tree()?;
+
+    // Trees are keyed by paths relative to the repository root.
+    let cwd = std::env::current_dir()?.canonicalize()?;
+    let root = match repo.work_dir() {
+        Some(work_dir) => work_dir.canonicalize()?,
+        None => cwd.clone(),
+    };
+    let tree_path = root_relative_path(&root, &cwd, path)?;
+
+    // Directories and submodules are rejected rather than read as blobs.
+    let Some((_, source_bytes)) = read_source(&tree, Path::new(&tree_path))? else {
+        bail!("{} not found in {revision}", path.display());
+    };
+
+    Ok(source_bytes)
+}
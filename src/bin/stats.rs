@@ -0,0 +1,58 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Summarize a database's contents, counting matches by codebase, revision,
+//! and kind. The database is only read.
+
+use anyhow::{bail, Context};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+const USAGE: &str = "Usage: stats [OPTIONS] DATABASE
+    --format text|json  Output format, text by default";
+
+fn main() -> anyhow::Result<()> {
+    let mut json = false;
+    let mut positional = Vec::<String>::new();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => match args
+                .next()
+                .context("--format requires text or json")?
+                .as_str()
+            {
+                "text" => json = false,
+                "json" => json = true,
+                format => bail!("Unknown format {format}, expected text or json"),
+            },
+            _ => positional.push(arg),
+        }
+    }
+
+    let [database] = positional.as_slice() else {
+        bail!(USAGE);
+    };
+
+    let conn = rawr::db::connect_ro(Path::new(database))?;
+    let stats = rawr::db::stats(&conn)?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+        return Ok(());
+    }
+
+    println!("{} matches.", stats.rows);
+    print_counts("Codebases", &stats.codebases);
+    print_counts("Revisions", &stats.revisions);
+    print_counts("Kinds", &stats.kinds);
+
+    Ok(())
+}
+
+fn print_counts(heading: &str, counts: &BTreeMap<String, usize>) {
+    println!("{heading}:");
+    for (name, count) in counts {
+        println!("\t{name}: {count}");
+    }
+}
@@ -0,0 +1,1072 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! SQLite persistence for matched items of interest.
+
+use crate::{Hash, Interesting};
+use anyhow::{anyhow, bail, Context};
+use rusqlite::types::{ToSql, ToSqlOutput, Type, ValueRef};
+use rusqlite::{params, Connection, OpenFlags, Row};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::io::Write;
+use std::path::Path;
+use std::sync::mpsc::{self, SyncSender};
+use std::thread::{self, JoinHandle};
+use tracing::warn;
+
+/// Ordered schema migrations. The database's `user_version` pragma records how
+/// many have been applied. Append new steps; never edit a released one.
+const MIGRATIONS: &[&str] = &[
+    // 1: Initial schema, mirroring `Interesting`.
+    "CREATE TABLE interesting (
+        codebase TEXT NOT NULL,
+        revision TEXT NOT NULL,
+        path TEXT NOT NULL,
+        start_byte INTEGER,
+        length INTEGER,
+        kind TEXT NOT NULL,
+        identifier TEXT NOT NULL,
+        hash_algorithm TEXT NOT NULL,
+        salt INTEGER NOT NULL,
+        hash TEXT NOT NULL,
+        notes TEXT
+    );",
+    // 2: Index the lookup key. Not UNIQUE, as overloaded or duplicated items
+    // legitimately share a key and are told apart by start_byte instead.
+    "CREATE INDEX interesting_key
+        ON interesting (codebase, revision, path, kind, identifier);",
+    // 3: Commit times, so history can be ordered without the repository.
+    "ALTER TABLE interesting ADD COLUMN committed_at INTEGER;",
+    // 4: Hashes as raw bytes rather than hex text. Blobs are stored as-is
+    // despite the column's TEXT affinity.
+    "UPDATE interesting SET hash = unhex(hash) WHERE typeof(hash) = 'text';",
+    // 5: Enclosing item names. NULL for rows scanned before this was tracked.
+    "ALTER TABLE interesting ADD COLUMN ancestors TEXT;",
+    // 6: Hashes with whitespace collapsed, using `hash_algorithm`. NULL for
+    // rows scanned before these were computed.
+    "ALTER TABLE interesting ADD COLUMN normalized_hash BLOB;",
+    // 7: Matches for each scanned blob, so that later runs can skip reading
+    // and parsing it. Keyed by the scanner's version, so rows cached with
+    // other matchers are never read.
+    "CREATE TABLE blob_cache (
+        version BLOB NOT NULL,
+        path TEXT NOT NULL,
+        oid TEXT NOT NULL,
+        PRIMARY KEY (version, path, oid)
+    );
+    CREATE TABLE blob_cache_match (
+        version BLOB NOT NULL,
+        path TEXT NOT NULL,
+        oid TEXT NOT NULL,
+        ancestors TEXT NOT NULL,
+        start_byte INTEGER,
+        length INTEGER,
+        kind TEXT NOT NULL,
+        identifier TEXT NOT NULL,
+        hash_algorithm TEXT NOT NULL,
+        salt INTEGER NOT NULL,
+        hash BLOB NOT NULL,
+        normalized_hash BLOB NOT NULL,
+        notes TEXT,
+        FOREIGN KEY (version, path, oid) REFERENCES blob_cache ON DELETE CASCADE
+    );
+    CREATE INDEX blob_cache_match_blob ON blob_cache_match (version, path, oid);",
+];
+
+/// Schema version that this build reads and writes.
+pub fn current_version() -> usize {
+    MIGRATIONS.len()
+}
+
+/// Open or create a database for writing, bringing its schema up to date.
+/// A path of `:memory:` opens an ephemeral database instead.
+pub fn connect_rw(path: &Path) -> anyhow::Result<Connection> {
+    if path == Path::new(":memory:") {
+        return connect_memory();
+    }
+
+    let conn =
+        Connection::open(path).with_context(|| format!("Open database {}", path.display()))?;
+    tune_for_writes(&conn)?;
+    prepare(conn)
+}
+
+/// Favor bulk insert throughput for scans. With WAL and `synchronous = NORMAL`,
+/// a power loss can lose the most recent transactions but won't corrupt the
+/// database. Lost matches are recovered by rescanning.
+fn tune_for_writes(conn: &Connection) -> anyhow::Result<()> {
+    let journal_mode: String =
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get(0))?;
+    if !journal_mode.eq_ignore_ascii_case("wal") {
+        bail!("Failed to enable WAL journal, got {journal_mode}");
+    }
+
+    conn.pragma_update(None, "synchronous", "NORMAL")?;
+    // Negative sizes are in KiB, so this is 64 MiB.
+    conn.pragma_update(None, "cache_size", -64_000)?;
+    Ok(())
+}
+
+/// Create an ephemeral database with the current schema. Nothing is persisted,
+/// which suits tests and one-shot scans.
+pub fn connect_memory() -> anyhow::Result<Connection> {
+    let conn = Connection::open_in_memory().context("Open in-memory database")?;
+    prepare(conn)
+}
+
+/// Shared setup for writable connections, so that file and memory databases
+/// can't drift apart.
+fn prepare(mut conn: Connection) -> anyhow::Result<Connection> {
+    conn.pragma_update(None, "foreign_keys", "ON")?;
+    migrate(&mut conn)?;
+    Ok(conn)
+}
+
+/// Open an existing database for reading. The schema must be current, as it
+/// can't be migrated without write access. Journal settings are left alone so
+/// that reading doesn't create WAL files.
+pub fn connect_ro(path: &Path) -> anyhow::Result<Connection> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("Open database {} read-only", path.display()))?;
+
+    let version = schema_version(&conn)?;
+    if version != current_version() {
+        bail!(
+            "Database schema is version {version}, expected {}. Open it read-write to migrate.",
+            current_version()
+        );
+    }
+
+    Ok(conn)
+}
+
+/// Read the schema version stored in the database header.
+pub fn schema_version(conn: &Connection) -> anyhow::Result<usize> {
+    let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    Ok(usize::try_from(version)?)
+}
+
+/// Apply any migrations newer than the database's stored version. Each step
+/// runs in its own transaction along with its version bump.
+pub fn migrate(conn: &mut Connection) -> anyhow::Result<()> {
+    let version = schema_version(conn)?;
+    if version > current_version() {
+        bail!(
+            "Database schema version {version} is newer than supported version {}",
+            current_version()
+        );
+    }
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)
+            .with_context(|| format!("Apply schema migration {}", index + 1))?;
+        tx.pragma_update(None, "user_version", (index + 1) as i64)?;
+        tx.commit()?;
+    }
+
+    Ok(())
+}
+
+/// Binds the raw digest. The algorithm is stored in its own column.
+impl ToSql for Hash {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::Borrowed(ValueRef::Blob(self.bytes())))
+    }
+}
+
+/// Read a hash from its `hash_algorithm` and `hash` columns. Digests that don't
+/// fit the algorithm are reported as conversion failures.
+pub fn read_hash(row: &Row, algorithm: usize, hash: usize) -> rusqlite::Result<Hash> {
+    let algorithm: String = row.get(algorithm)?;
+    let bytes: Vec<u8> = row.get(hash)?;
+    Hash::from_bytes(&algorithm, &bytes)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(hash, Type::Blob, e.into()))
+}
+
+/// Read a hash whose column may be NULL, such as one added after the row was
+/// scanned.
+pub fn read_optional_hash(
+    row: &Row,
+    algorithm: usize,
+    hash: usize,
+) -> rusqlite::Result<Option<Hash>> {
+    match row.get_ref(hash)? {
+        ValueRef::Null => Ok(None),
+        _ => read_hash(row, algorithm, hash).map(Some),
+    }
+}
+
+/// Outcome of a batch insert.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct InsertSummary {
+    /// New rows written.
+    pub inserted: usize,
+    /// Rows already present with the same hash, such as from a rescrape.
+    pub skipped: usize,
+    /// Rows already present with a different hash. These are not written.
+    pub conflicts: usize,
+}
+
+/// Insert matched items in a single transaction. Items that are already known
+/// are skipped rather than aborting the batch, and conflicting items are
+/// reported without being written.
+pub fn insert_batch(conn: &mut Connection, items: &[Interesting]) -> anyhow::Result<InsertSummary> {
+    let mut summary = InsertSummary::default();
+
+    let tx = conn.transaction()?;
+    {
+        let mut existing = tx.prepare(
+            "SELECT hash_algorithm, hash FROM interesting
+            WHERE codebase = ? AND revision = ? AND path = ? AND kind = ? AND identifier = ?
+                AND start_byte IS ?
+                -- Rows scanned before ancestors were tracked have none.
+                AND (ancestors IS ? OR ancestors IS NULL)",
+        )?;
+        let mut insert = tx.prepare(
+            "INSERT INTO interesting
+            (codebase, revision, committed_at, path, ancestors, start_byte, length, kind,
+                identifier, hash_algorithm, salt, hash, normalized_hash, notes)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )?;
+
+        for item in items {
+            let hashes = existing
+                .query_map(
+                    params![
+                        item.codebase,
+                        item.revision,
+                        item.path,
+                        item.kind,
+                        item.identifier,
+                        item.start_byte,
+                        item.ancestors,
+                    ],
+                    |row| read_hash(row, 0, 1),
+                )?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            if hashes.is_empty() {
+                insert.execute(params![
+                    item.codebase,
+                    item.revision,
+                    item.committed_at,
+                    item.path,
+                    item.ancestors,
+                    item.start_byte,
+                    item.length,
+                    item.kind,
+                    item.identifier,
+                    item.hash.algorithm(),
+                    // SQLite integers are signed. Keep the bits.
+                    item.salt as i64,
+                    item.hash,
+                    item.normalized_hash,
+                    item.notes,
+                ])?;
+                summary.inserted += 1;
+            } else if hashes.contains(&item.hash) {
+                summary.skipped += 1;
+            } else {
+                warn!(
+                    "Conflicting hash for {} {} in {}@{}",
+                    item.kind, item.identifier, item.path, item.revision
+                );
+                summary.conflicts += 1;
+            }
+        }
+    }
+    tx.commit()?;
+
+    Ok(summary)
+}
+
+/// Batches that can be queued for a `Writer` before senders block.
+const WRITER_QUEUE: usize = 16;
+
+/// Inserts batches on a dedicated thread, so that scans on other threads can
+/// queue matches without sharing a connection. SQLite only allows one writer
+/// at a time, so a single writing connection never hits `database is locked`.
+pub struct Writer {
+    sender: SyncSender<Vec<Interesting>>,
+    handle: JoinHandle<anyhow::Result<InsertSummary>>,
+}
+
+impl Writer {
+    /// Open a database for writing, and start inserting queued batches.
+    pub fn spawn(path: &Path) -> anyhow::Result<Self> {
+        let mut conn = connect_rw(path)?;
+        let (sender, receiver) = mpsc::sync_channel::<Vec<Interesting>>(WRITER_QUEUE);
+        let handle = thread::spawn(move || {
+            let mut summary = InsertSummary::default();
+            for batch in receiver {
+                let inserted = insert_batch(&mut conn, &batch)?;
+                summary.inserted += inserted.inserted;
+                summary.skipped += inserted.skipped;
+                summary.conflicts += inserted.conflicts;
+            }
+            Ok(summary)
+        });
+
+        Ok(Writer { sender, handle })
+    }
+
+    /// Sender for queueing batches from other threads.
+    pub fn sender(&self) -> SyncSender<Vec<Interesting>> {
+        self.sender.clone()
+    }
+
+    /// Queue a batch for insertion, waiting if the queue is full.
+    pub fn send(&self, batch: Vec<Interesting>) -> anyhow::Result<()> {
+        self.sender
+            .send(batch)
+            .map_err(|_| anyhow!("Database writer stopped. Finish it to see why."))
+    }
+
+    /// Wait for every queued batch to be inserted, returning the totals.
+    /// Batches can be queued until every sender is dropped.
+    pub fn finish(self) -> anyhow::Result<InsertSummary> {
+        drop(self.sender);
+        self.handle
+            .join()
+            .map_err(|_| anyhow!("Database writer panicked"))?
+    }
+}
+
+/// Every revision that has been scanned for a codebase.
+pub fn scanned_revisions(conn: &Connection, codebase: &str) -> anyhow::Result<HashSet<String>> {
+    let mut statement =
+        conn.prepare("SELECT DISTINCT revision FROM interesting WHERE codebase = ?")?;
+    let revisions = statement
+        .query_map([codebase], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    Ok(revisions)
+}
+
+/// Matches cached for a blob at a path by an earlier scan with the same scanner
+/// version, or `None` if it hasn't been scanned. Codebase, revision, and commit
+/// time are left empty for the caller to fill in.
+pub fn cached_matches(
+    conn: &Connection,
+    version: &Hash,
+    path: &str,
+    oid: &str,
+) -> anyhow::Result<Option<Vec<Interesting>>> {
+    let cached = conn
+        .prepare_cached("SELECT 1 FROM blob_cache WHERE version = ? AND path = ? AND oid = ?")?
+        .exists(params![version, path, oid])?;
+    if !cached {
+        return Ok(None);
+    }
+
+    let mut statement = conn.prepare_cached(
+        "SELECT ancestors, start_byte, length, kind, identifier, hash_algorithm, salt, hash,
+            normalized_hash, notes
+        FROM blob_cache_match
+        WHERE version = ? AND path = ? AND oid = ?
+        ORDER BY rowid",
+    )?;
+    let matches = statement
+        .query_map(params![version, path, oid], |row| {
+            Ok(Interesting {
+                codebase: String::new(),
+                revision: String::new(),
+                committed_at: None,
+                path: path.to_string(),
+                ancestors: row.get(0)?,
+                start_byte: row.get(1)?,
+                length: row.get(2)?,
+                kind: row.get(3)?,
+                identifier: row.get(4)?,
+                salt: row.get::<_, i64>(6)? as u64,
+                hash: read_hash(row, 5, 7)?,
+                normalized_hash: read_hash(row, 5, 8)?,
+                notes: row.get(9)?,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+    Ok(Some(matches))
+}
+
+/// Cache the matches for a blob at a path, including an empty set for files
+/// without any. Blobs that are already cached are left alone.
+pub fn cache_matches(
+    conn: &mut Connection,
+    version: &Hash,
+    path: &str,
+    oid: &str,
+    matches: &[Interesting],
+) -> anyhow::Result<()> {
+    let tx = conn.transaction()?;
+    let added = tx.execute(
+        "INSERT OR IGNORE INTO blob_cache (version, path, oid) VALUES (?, ?, ?)",
+        params![version, path, oid],
+    )?;
+    if added > 0 {
+        let mut insert = tx.prepare(
+            "INSERT INTO blob_cache_match
+            (version, path, oid, ancestors, start_byte, length, kind, identifier,
+                hash_algorithm, salt, hash, normalized_hash, notes)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )?;
+        for item in matches {
+            insert.execute(params![
+                version,
+                path,
+                oid,
+                item.ancestors,
+                item.start_byte,
+                item.length,
+                item.kind,
+                item.identifier,
+                item.hash.algorithm(),
+                item.salt as i64,
+                item.hash,
+                item.normalized_hash,
+                item.notes,
+            ])?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Row counts summarizing a database.
+// DESIGN Watch states live in annotations rather than the database, so they
+// aren't counted here.
+#[derive(Debug, Default, Eq, PartialEq, Serialize)]
+pub struct Stats {
+    pub rows: usize,
+    pub codebases: BTreeMap<String, usize>,
+    pub revisions: BTreeMap<String, usize>,
+    pub kinds: BTreeMap<String, usize>,
+}
+
+/// Count stored matches by codebase, revision, and kind.
+pub fn stats(conn: &Connection) -> anyhow::Result<Stats> {
+    let count_by = |column: &str| -> anyhow::Result<BTreeMap<String, usize>> {
+        let mut statement = conn.prepare(&format!(
+            "SELECT {column}, COUNT(*) FROM interesting GROUP BY {column}"
+        ))?;
+        let counts = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as usize)))?
+            .collect::<Result<_, _>>()?;
+        Ok(counts)
+    };
+
+    let codebases = count_by("codebase")?;
+    Ok(Stats {
+        rows: codebases.values().sum(),
+        codebases,
+        revisions: count_by("revision")?,
+        kinds: count_by("kind")?,
+    })
+}
+
+/// Restricts exported rows. Unset fields match every row.
+#[derive(Debug, Default)]
+pub struct ExportFilter {
+    pub codebase: Option<String>,
+    pub revision: Option<String>,
+    pub kind: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExportFormat {
+    /// One JSON object per line.
+    JsonLines,
+    /// Comma-separated values with a header row.
+    Csv,
+}
+
+/// Stored match as exported, with hashes rendered as hex.
+#[derive(Debug, Serialize)]
+pub struct ExportRow {
+    pub codebase: String,
+    pub revision: String,
+    pub committed_at: Option<i64>,
+    pub path: String,
+    pub ancestors: Option<String>,
+    pub start_byte: Option<i64>,
+    pub length: Option<i64>,
+    pub kind: String,
+    pub identifier: String,
+    pub hash_algorithm: String,
+    pub salt: u64,
+    pub hash: String,
+    pub normalized_hash: Option<String>,
+    pub notes: Option<String>,
+}
+
+/// Column names in CSV output, matching `ExportRow`'s fields.
+const EXPORT_COLUMNS: &[&str] = &[
+    "codebase",
+    "revision",
+    "committed_at",
+    "path",
+    "ancestors",
+    "start_byte",
+    "length",
+    "kind",
+    "identifier",
+    "hash_algorithm",
+    "salt",
+    "hash",
+    "normalized_hash",
+    "notes",
+];
+
+impl ExportRow {
+    fn csv_fields(&self) -> [String; EXPORT_COLUMNS.len()] {
+        let optional = |value: &Option<String>| value.clone().unwrap_or_default();
+        let number = |value: Option<i64>| value.map(|value| value.to_string()).unwrap_or_default();
+        [
+            self.codebase.clone(),
+            self.revision.clone(),
+            number(self.committed_at),
+            self.path.clone(),
+            optional(&self.ancestors),
+            number(self.start_byte),
+            number(self.length),
+            self.kind.clone(),
+            self.identifier.clone(),
+            self.hash_algorithm.clone(),
+            self.salt.to_string(),
+            self.hash.clone(),
+            optional(&self.normalized_hash),
+            optional(&self.notes),
+        ]
+    }
+}
+
+/// Write stored matches as they are read, so that large tables aren't held in
+/// memory. Returns the number of rows written.
+pub fn export(
+    conn: &Connection,
+    filter: &ExportFilter,
+    format: ExportFormat,
+    out: &mut dyn Write,
+) -> anyhow::Result<usize> {
+    let mut statement = conn.prepare(
+        "SELECT codebase, revision, committed_at, path, ancestors, start_byte, length, kind,
+            identifier, hash_algorithm, salt, hash, normalized_hash, notes
+        FROM interesting
+        WHERE (?1 IS NULL OR codebase = ?1)
+            AND (?2 IS NULL OR revision = ?2)
+            AND (?3 IS NULL OR kind = ?3)
+        ORDER BY codebase, committed_at, revision, path, start_byte",
+    )?;
+    let rows = statement.query_map(
+        params![filter.codebase, filter.revision, filter.kind],
+        |row| {
+            Ok(ExportRow {
+                codebase: row.get(0)?,
+                revision: row.get(1)?,
+                committed_at: row.get(2)?,
+                path: row.get(3)?,
+                ancestors: row.get(4)?,
+                start_byte: row.get(5)?,
+                length: row.get(6)?,
+                kind: row.get(7)?,
+                identifier: row.get(8)?,
+                hash_algorithm: row.get(9)?,
+                // Stored signed. Restore the original bits.
+                salt: row.get::<_, i64>(10)? as u64,
+                hash: read_hash(row, 9, 11)?.to_hex(),
+                normalized_hash: read_optional_hash(row, 9, 12)?.map(|hash| hash.to_hex()),
+                notes: row.get(13)?,
+            })
+        },
+    )?;
+
+    if format == ExportFormat::Csv {
+        write_csv_record(out, EXPORT_COLUMNS)?;
+    }
+
+    let mut count = 0;
+    for row in rows {
+        let row = row?;
+        match format {
+            ExportFormat::JsonLines => {
+                serde_json::to_writer(&mut *out, &row)?;
+                writeln!(out)?;
+            }
+            ExportFormat::Csv => write_csv_record(out, &row.csv_fields())?,
+        }
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+/// Write a CSV record, quoting fields that contain separators, quotes, or line
+/// breaks.
+fn write_csv_record<S: AsRef<str>>(out: &mut dyn Write, fields: &[S]) -> anyhow::Result<()> {
+    for (index, field) in fields.iter().enumerate() {
+        if index > 0 {
+            write!(out, ",")?;
+        }
+        let field = field.as_ref();
+        if field.contains([',', '"', '\n', '\r']) {
+            write!(out, "\"{}\"", field.replace('"', "\"\""))?;
+        } else {
+            write!(out, "{field}")?;
+        }
+    }
+    writeln!(out)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(identifier: &str, start_byte: usize, hash: u8) -> Interesting {
+        Interesting {
+            codebase: "codebase".to_string(),
+            revision: "rev".to_string(),
+            committed_at: Some(1_700_000_000),
+            path: "src/lib.rs".to_string(),
+            ancestors: "Widget".to_string(),
+            start_byte: Some(start_byte),
+            length: Some(10),
+            kind: "function".to_string(),
+            identifier: identifier.to_string(),
+            salt: u64::MAX,
+            hash: Hash::Sha256([hash; 32]),
+            normalized_hash: Hash::Sha256([hash ^ 0xff; 32]),
+            notes: Some("note".to_string()),
+        }
+    }
+
+    /// Unique path for a database file in the temporary directory.
+    fn temp_database() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("rawr-test-{:016x}.db", rand::random::<u64>()))
+    }
+
+    /// Remove a database file along with its WAL files.
+    fn remove_database(path: &Path) {
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+        }
+    }
+
+    #[test]
+    fn migrate_old_version_to_current() {
+        // A database as written by the first release, before hashes were blobs.
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(MIGRATIONS[0]).unwrap();
+        conn.pragma_update(None, "user_version", 1).unwrap();
+        conn.execute(
+            "INSERT INTO interesting
+            (codebase, revision, path, kind, identifier, hash_algorithm, salt, hash)
+            VALUES ('codebase', 'rev', 'a.rs', 'function', 'f', 'sha256', 0, ?)",
+            [Hash::Sha256([0xab; 32]).to_hex()],
+        )
+        .unwrap();
+
+        migrate(&mut conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), current_version());
+
+        let hash = conn
+            .query_row("SELECT hash_algorithm, hash FROM interesting", [], |row| {
+                read_hash(row, 0, 1)
+            })
+            .unwrap();
+        assert_eq!(hash, Hash::Sha256([0xab; 32]));
+
+        // Already current, so migrating again changes nothing.
+        migrate(&mut conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), current_version());
+    }
+
+    #[test]
+    fn reject_newer_version() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", (current_version() + 1) as i64)
+            .unwrap();
+        assert!(migrate(&mut conn).is_err());
+    }
+
+    #[test]
+    fn memory_round_trip() {
+        let mut conn = connect_memory().unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), current_version());
+
+        let stored = item("draw", 100, 1);
+        insert_batch(&mut conn, std::slice::from_ref(&stored)).unwrap();
+
+        let loaded = conn
+            .query_row(
+                "SELECT codebase, revision, committed_at, path, ancestors, start_byte, length,
+                    kind, identifier, salt, hash_algorithm, hash, normalized_hash, notes
+                FROM interesting",
+                [],
+                |row| {
+                    Ok(Interesting {
+                        codebase: row.get(0)?,
+                        revision: row.get(1)?,
+                        committed_at: row.get(2)?,
+                        path: row.get(3)?,
+                        ancestors: row.get(4)?,
+                        start_byte: row.get(5)?,
+                        length: row.get(6)?,
+                        kind: row.get(7)?,
+                        identifier: row.get(8)?,
+                        salt: row.get::<_, i64>(9)? as u64,
+                        hash: read_hash(row, 10, 11)?,
+                        normalized_hash: read_hash(row, 10, 12)?,
+                        notes: row.get(13)?,
+                    })
+                },
+            )
+            .unwrap();
+        assert_eq!(loaded, stored);
+    }
+
+    #[test]
+    fn read_hash_rejects_wrong_length() {
+        let conn = connect_memory().unwrap();
+        let read = |bytes: Vec<u8>| {
+            conn.query_row("SELECT 'sha256', ?", [bytes], |row| read_hash(row, 0, 1))
+        };
+
+        assert_eq!(read(vec![3; 32]).unwrap(), Hash::Sha256([3; 32]));
+        let Err(rusqlite::Error::FromSqlConversionFailure(index, Type::Blob, _)) =
+            read(vec![3; 20])
+        else {
+            panic!("Expected a conversion failure for a short hash");
+        };
+        assert_eq!(index, 1);
+    }
+
+    #[test]
+    fn read_optional_hash_allows_null() {
+        let mut conn = connect_memory().unwrap();
+        insert_batch(&mut conn, &[item("draw", 100, 1)]).unwrap();
+        let read = |conn: &Connection| {
+            conn.query_row(
+                "SELECT hash_algorithm, normalized_hash FROM interesting",
+                [],
+                |row| read_optional_hash(row, 0, 1),
+            )
+        };
+
+        assert_eq!(read(&conn).unwrap(), Some(Hash::Sha256([0xfe; 32])));
+        conn.execute("UPDATE interesting SET normalized_hash = NULL", [])
+            .unwrap();
+        assert_eq!(read(&conn).unwrap(), None);
+    }
+
+    #[test]
+    fn memory_path_is_ephemeral() {
+        let mut conn = connect_rw(Path::new(":memory:")).unwrap();
+        insert_batch(&mut conn, &[item("draw", 100, 1)]).unwrap();
+
+        let conn = connect_rw(Path::new(":memory:")).unwrap();
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM interesting", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn lookup_uses_key_index() {
+        let conn = connect_memory().unwrap();
+        let mut plan = conn
+            .prepare(
+                "EXPLAIN QUERY PLAN
+                SELECT hash_algorithm, hash FROM interesting
+                WHERE codebase = ? AND revision = ? AND path = ? AND kind = ? AND identifier = ?",
+            )
+            .unwrap();
+        let details = plan
+            .query_map(["codebase", "rev", "a.rs", "function", "f"], |row| {
+                row.get::<_, String>(3)
+            })
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert!(
+            details
+                .iter()
+                .any(|detail| detail.contains("USING INDEX interesting_key")),
+            "Expected an index lookup, got {details:?}"
+        );
+    }
+
+    #[test]
+    fn insert_outcomes() {
+        let mut conn = connect_memory().unwrap();
+
+        let first = insert_batch(&mut conn, &[item("draw", 100, 1), item("draw", 200, 2)]);
+        assert_eq!(
+            first.unwrap(),
+            InsertSummary {
+                inserted: 2,
+                skipped: 0,
+                conflicts: 0,
+            }
+        );
+
+        // Rescanning finds the same items, one of them changed in place.
+        let second = insert_batch(&mut conn, &[item("draw", 100, 1), item("draw", 200, 3)]);
+        assert_eq!(
+            second.unwrap(),
+            InsertSummary {
+                inserted: 0,
+                skipped: 1,
+                conflicts: 1,
+            }
+        );
+
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM interesting", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn insert_distinguishes_ancestors() {
+        let mut conn = connect_memory().unwrap();
+        let in_gadget = Interesting {
+            ancestors: "Gadget".to_string(),
+            ..item("draw", 100, 2)
+        };
+
+        let summary = insert_batch(&mut conn, &[item("draw", 100, 1), in_gadget.clone()]);
+        assert_eq!(summary.unwrap().inserted, 2);
+        let summary = insert_batch(&mut conn, &[item("draw", 100, 1), in_gadget]);
+        assert_eq!(summary.unwrap().skipped, 2);
+
+        // Rows from before ancestors were stored still count as known.
+        conn.execute("UPDATE interesting SET ancestors = NULL", [])
+            .unwrap();
+        let summary = insert_batch(&mut conn, &[item("draw", 100, 1)]).unwrap();
+        assert_eq!((summary.inserted, summary.skipped), (0, 1));
+    }
+
+    #[test]
+    fn stats_count_by_codebase_revision_and_kind() {
+        let mut conn = connect_memory().unwrap();
+        assert_eq!(stats(&conn).unwrap(), Stats::default());
+
+        let upstream = |revision: &str, kind: &str, start_byte| Interesting {
+            codebase: "upstream".to_string(),
+            revision: revision.to_string(),
+            kind: kind.to_string(),
+            ..item("draw", start_byte, 1)
+        };
+        let items = [
+            item("draw", 100, 1),
+            upstream("r1", "function", 100),
+            upstream("r1", "struct", 200),
+            upstream("r2", "function", 100),
+        ];
+        insert_batch(&mut conn, &items).unwrap();
+
+        let counts = |pairs: &[(&str, usize)]| {
+            pairs
+                .iter()
+                .map(|(name, count)| (name.to_string(), *count))
+                .collect()
+        };
+        assert_eq!(
+            stats(&conn).unwrap(),
+            Stats {
+                rows: 4,
+                codebases: counts(&[("codebase", 1), ("upstream", 3)]),
+                revisions: counts(&[("r1", 2), ("r2", 1), ("rev", 1)]),
+                kinds: counts(&[("function", 3), ("struct", 1)]),
+            }
+        );
+    }
+
+    #[test]
+    fn export_json_lines_round_trip() {
+        let mut conn = connect_memory().unwrap();
+        let other = Interesting {
+            kind: "struct".to_string(),
+            ..item("Widget", 0, 2)
+        };
+        insert_batch(&mut conn, &[item("draw", 100, 1), other]).unwrap();
+
+        let mut out = Vec::new();
+        let filter = ExportFilter {
+            kind: Some("function".to_string()),
+            ..ExportFilter::default()
+        };
+        let count = export(&conn, &filter, ExportFormat::JsonLines, &mut out).unwrap();
+        assert_eq!(count, 1);
+
+        let out = String::from_utf8(out).unwrap();
+        let rows = out
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        let [row] = rows.as_slice() else {
+            panic!("Expected one row: {out}");
+        };
+        assert_eq!(row["identifier"], "draw");
+        assert_eq!(row["start_byte"], 100);
+        assert_eq!(row["salt"], u64::MAX);
+        assert_eq!(row["hash"], Hash::Sha256([1; 32]).to_hex());
+        assert_eq!(row["normalized_hash"], Hash::Sha256([0xfe; 32]).to_hex());
+        assert_eq!(row["notes"], "note");
+    }
+
+    #[test]
+    fn export_csv_quotes_fields() {
+        let mut conn = connect_memory().unwrap();
+        let quoted = Interesting {
+            notes: Some("a, \"b\"\nc".to_string()),
+            ..item("draw", 100, 1)
+        };
+        insert_batch(&mut conn, &[quoted]).unwrap();
+        conn.execute("UPDATE interesting SET normalized_hash = NULL", [])
+            .unwrap();
+
+        let mut out = Vec::new();
+        let filter = ExportFilter {
+            codebase: Some("codebase".to_string()),
+            revision: Some("rev".to_string()),
+            kind: None,
+        };
+        export(&conn, &filter, ExportFormat::Csv, &mut out).unwrap();
+
+        let hash = Hash::Sha256([1; 32]).to_hex();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                "{}\ncodebase,rev,1700000000,src/lib.rs,Widget,100,10,function,draw,sha256,\
+                {},{hash},,\"a, \"\"b\"\"\nc\"\n",
+                EXPORT_COLUMNS.join(","),
+                u64::MAX,
+            )
+        );
+
+        // Filters that match nothing leave only the header.
+        let mut out = Vec::new();
+        let filter = ExportFilter {
+            revision: Some("other".to_string()),
+            ..ExportFilter::default()
+        };
+        assert_eq!(
+            export(&conn, &filter, ExportFormat::Csv, &mut out).unwrap(),
+            0
+        );
+        assert_eq!(out.iter().filter(|byte| **byte == b'\n').count(), 1);
+    }
+
+    #[test]
+    fn cache_round_trip() {
+        let mut conn = connect_memory().unwrap();
+        let version = Hash::Sha256([7; 32]);
+        let oid = "0123456789abcdef0123456789abcdef01234567";
+        let items = [item("draw", 100, 1), item("fill", 200, 2)];
+
+        assert_eq!(
+            cached_matches(&conn, &version, "src/lib.rs", oid).unwrap(),
+            None
+        );
+        cache_matches(&mut conn, &version, "src/lib.rs", oid, &items).unwrap();
+        cache_matches(&mut conn, &version, "src/empty.rs", oid, &[]).unwrap();
+        // Caching again doesn't duplicate matches.
+        cache_matches(&mut conn, &version, "src/lib.rs", oid, &items).unwrap();
+
+        let cached = cached_matches(&conn, &version, "src/lib.rs", oid)
+            .unwrap()
+            .unwrap();
+        let expected = items.map(|item| Interesting {
+            codebase: String::new(),
+            revision: String::new(),
+            committed_at: None,
+            ..item
+        });
+        assert_eq!(cached, expected);
+        assert_eq!(
+            cached_matches(&conn, &version, "src/empty.rs", oid).unwrap(),
+            Some(Vec::new())
+        );
+
+        // Other versions never see these.
+        let other = Hash::Sha256([8; 32]);
+        assert_eq!(
+            cached_matches(&conn, &other, "src/lib.rs", oid).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn writer_accepts_batches_from_many_threads() {
+        let path = temp_database();
+        let writer = Writer::spawn(&path).unwrap();
+
+        let scanners = (0..4)
+            .map(|thread| {
+                let sender = writer.sender();
+                thread::spawn(move || {
+                    for batch in 0..10 {
+                        let items = (0..25)
+                            .map(|index| {
+                                let mut item = item("draw", index, 1);
+                                item.revision = format!("rev-{thread}-{batch}");
+                                item
+                            })
+                            .collect();
+                        sender.send(items).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for scanner in scanners {
+            scanner.join().unwrap();
+        }
+        // Rescanned items are skipped, even when queued from another thread.
+        writer.send(vec![item("draw", 0, 1)]).unwrap();
+        writer.send(vec![item("draw", 0, 1)]).unwrap();
+
+        let summary = writer.finish().unwrap();
+        assert_eq!(
+            summary,
+            InsertSummary {
+                inserted: 1001,
+                skipped: 1,
+                conflicts: 0,
+            }
+        );
+
+        let conn = connect_ro(&path).unwrap();
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM interesting", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1001);
+
+        drop(conn);
+        remove_database(&path);
+    }
+
+    #[test]
+    fn file_database_is_tuned_for_writes() {
+        let path = temp_database();
+        let conn = connect_rw(&path).unwrap();
+
+        let journal_mode: String = conn
+            .pragma_query_value(None, "journal_mode", |row| row.get(0))
+            .unwrap();
+        let synchronous: i64 = conn
+            .pragma_query_value(None, "synchronous", |row| row.get(0))
+            .unwrap();
+        let cache_size: i64 = conn
+            .pragma_query_value(None, "cache_size", |row| row.get(0))
+            .unwrap();
+        assert_eq!(journal_mode, "wal");
+        // NORMAL
+        assert_eq!(synchronous, 1);
+        assert_eq!(cache_size, -64_000);
+
+        drop(conn);
+        remove_database(&path);
+    }
+}
@@ -0,0 +1,390 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Structured descriptions of query matches, for inspecting queries while
+//! developing matchers. Callers decide how to format them.
+
+use crate::lang::satisfies_predicates;
+use std::ops::Range;
+use tracing::warn;
+use tree_sitter::{Node, Query, QueryCursor, Tree};
+
+/// One match of a query, with its captures in match order.
+#[derive(Debug, Eq, PartialEq)]
+pub struct MatchDescription {
+    pub pattern_index: usize,
+    pub captures: Vec<CaptureDescription>,
+}
+
+/// A captured node and its text.
+#[derive(Debug, Eq, PartialEq)]
+pub struct CaptureDescription {
+    /// Index of the capture in the query.
+    pub index: u32,
+    /// Capture name, without the leading `@`.
+    pub name: String,
+    /// Tree-Sitter kind of the node, such as `function_item`.
+    pub kind: String,
+    pub range: Range<usize>,
+    /// Text of the node. Invalid UTF-8 is replaced.
+    pub text: String,
+    pub sexp: String,
+    pub named_children: usize,
+    /// Kind-specific summary lines, such as `name -> body` for Bash functions
+    /// or the arguments of an attribute.
+    pub details: Vec<String>,
+}
+
+/// Run a query over a parsed tree and describe every match that satisfies the
+/// query's predicates.
+pub fn describe_matches(query: &Query, source_bytes: &[u8], tree: &Tree) -> Vec<MatchDescription> {
+    let mut cursor = QueryCursor::new();
+    cursor
+        .matches(query, tree.root_node(), source_bytes)
+        .filter(|matched| satisfies_predicates(query, matched, source_bytes))
+        .map(|matched| MatchDescription {
+            pattern_index: matched.pattern_index,
+            captures: matched
+                .captures
+                .iter()
+                .map(|cap| CaptureDescription {
+                    index: cap.index,
+                    name: query.capture_names()[cap.index as usize].clone(),
+                    kind: cap.node.kind().to_string(),
+                    range: cap.node.byte_range(),
+                    text: text(cap.node, source_bytes),
+                    sexp: cap.node.to_sexp(),
+                    named_children: cap.node.named_child_count(),
+                    details: details(cap.node, source_bytes),
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+/// Grammars with named children are easier to pick apart.
+fn details(node: Node, source_bytes: &[u8]) -> Vec<String> {
+    match node.kind() {
+        "function_definition" => {
+            let name = node.child_by_field_name("name");
+            let body = node.child_by_field_name("body");
+            let (Some(name), Some(body)) = (name, body) else {
+                return Vec::new();
+            };
+            vec![format!(
+                "{} -> {:?}",
+                text(name, source_bytes),
+                text(body, source_bytes)
+            )]
+        }
+        "variable_assignment" => {
+            let name = node.child_by_field_name("name");
+            let value = node.child_by_field_name("value");
+            let (Some(name), Some(value)) = (name, value) else {
+                return Vec::new();
+            };
+            vec![format!(
+                "{} = {:?}",
+                text(name, source_bytes),
+                text(value, source_bytes)
+            )]
+        }
+        "attribute" => parse_attribute_args(node, source_bytes)
+            .into_iter()
+            .map(|arg| match arg.string_value() {
+                Some(value) => format!("Argument: {} = ({}) {:?}", arg.key, arg.kind, value),
+                None => format!("Argument: {} = ({}) {}", arg.key, arg.kind, arg.value),
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn text(node: Node, source_bytes: &[u8]) -> String {
+    String::from_utf8_lossy(&source_bytes[node.byte_range()]).to_string()
+}
+
+/// Key-value argument from a `#[rawr(...)]` attribute.
+#[derive(Debug, Eq, PartialEq)]
+pub struct AttributeArgument {
+    pub key: String,
+    /// Tree-Sitter kind of the value, such as `string_literal`.
+    pub kind: String,
+    /// Value as written in the source.
+    pub value: String,
+}
+
+impl AttributeArgument {
+    /// Contents of a string or raw string value, without quotes or raw
+    /// delimiters and with escapes resolved. Strings may span several lines.
+    /// `None` for other kinds of value, or malformed strings.
+    pub fn string_value(&self) -> Option<String> {
+        match self.kind.as_str() {
+            "string_literal" | "raw_string_literal" => unescape_string_literal(&self.value),
+            _ => None,
+        }
+    }
+}
+
+/// Resolve a Rust string literal as written, such as `"a\"b"` or `r#"a"b"#`,
+/// to its value.
+pub fn unescape_string_literal(literal: &str) -> Option<String> {
+    // Raw strings have no escapes. Strip the delimiters and take the rest as-is.
+    if let Some(raw) = literal.strip_prefix('r') {
+        let hashes = raw.len() - raw.trim_start_matches('#').len();
+        let delimiter = "#".repeat(hashes);
+        return raw
+            .strip_prefix(&delimiter)?
+            .strip_prefix('"')?
+            .strip_suffix(&delimiter)?
+            .strip_suffix('"')
+            .map(str::to_string);
+    }
+
+    let inner = literal.strip_prefix('"')?.strip_suffix('"')?;
+    let mut value = String::with_capacity(inner.len());
+    let mut chars = inner.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+
+        match chars.next()? {
+            'n' => value.push('\n'),
+            'r' => value.push('\r'),
+            't' => value.push('\t'),
+            '0' => value.push('\0'),
+            '\\' => value.push('\\'),
+            '\'' => value.push('\''),
+            '"' => value.push('"'),
+            'x' => {
+                let digits = [chars.next()?, chars.next()?].iter().collect::<String>();
+                let byte = u8::from_str_radix(&digits, 16).ok().filter(u8::is_ascii)?;
+                value.push(char::from(byte));
+            }
+            'u' => {
+                if chars.next()? != '{' {
+                    return None;
+                }
+                let mut digits = String::new();
+                for c in chars.by_ref() {
+                    if c == '}' {
+                        break;
+                    }
+                    if c != '_' {
+                        digits.push(c);
+                    }
+                }
+                value.push(char::from_u32(u32::from_str_radix(&digits, 16).ok()?)?);
+            }
+            // Line continuation drops the newline and the next line's indent.
+            '\n' | '\r' => while chars.next_if(|c| c.is_whitespace()).is_some() {},
+            _ => return None,
+        }
+    }
+
+    Some(value)
+}
+
+/// Extract the `key = value` arguments from an attribute node. Arguments are
+/// read as a sequence of `key`, `=`, value, and an optional trailing comma, so
+/// that commas or parentheses inside a value can't split it. Malformed
+/// arguments are skipped up to the next comma.
+pub fn parse_attribute_args(attribute: Node, source_bytes: &[u8]) -> Vec<AttributeArgument> {
+    let Some(args) = attribute.child_by_field_name("arguments") else {
+        return Vec::new();
+    };
+
+    // Skip the token tree's opening and closing delimiters.
+    let mut tree_cursor = args.walk();
+    let children = args.children(&mut tree_cursor).collect::<Vec<_>>();
+    let inner = children
+        .get(1..children.len().saturating_sub(1))
+        .unwrap_or_default();
+
+    let mut parsed = Vec::new();
+    for argument in inner.split(|node| node.kind() == ",") {
+        let [key, equals, val] = argument else {
+            if !argument.is_empty() {
+                let skipped = argument
+                    .iter()
+                    .map(|node| text(*node, source_bytes))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                warn!("Skipping malformed attribute argument: {skipped}");
+            }
+            continue;
+        };
+        if key.kind() != "identifier" || equals.kind() != "=" {
+            warn!(
+                "Skipping malformed attribute argument starting with {}",
+                text(*key, source_bytes)
+            );
+            continue;
+        }
+
+        parsed.push(AttributeArgument {
+            key: text(*key, source_bytes),
+            kind: val.kind().to_string(),
+            value: text(*val, source_bytes),
+        });
+    }
+
+    parsed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tree_sitter::Parser;
+
+    fn parse(language: tree_sitter::Language, source: &str) -> Tree {
+        let mut parser = Parser::new();
+        parser.set_language(language).unwrap();
+        parser.parse(source, None).unwrap()
+    }
+
+    #[test]
+    fn describe_bash_matches() {
+        let source = "greet() { echo hi; }\nFOO=bar\n";
+        let language = tree_sitter_bash::language();
+        let tree = parse(language, source);
+        let query = Query::new(
+            language,
+            "((function_definition) @def)\n((variable_assignment) @var (#eq? @var \"FOO=bar\"))",
+        )
+        .unwrap();
+
+        let described = describe_matches(&query, source.as_bytes(), &tree);
+        assert_eq!(described.len(), 2);
+
+        let function = &described[0];
+        assert_eq!(function.pattern_index, 0);
+        let [capture] = function.captures.as_slice() else {
+            panic!("Expected a single capture: {function:?}");
+        };
+        assert_eq!(capture.name, "def");
+        assert_eq!(capture.kind, "function_definition");
+        assert_eq!(capture.range, 0..20);
+        assert_eq!(capture.text, "greet() { echo hi; }");
+        assert!(capture.sexp.starts_with("(function_definition"));
+        assert_eq!(capture.details, ["greet -> \"{ echo hi; }\""]);
+
+        let variable = &described[1];
+        assert_eq!(variable.pattern_index, 1);
+        assert_eq!(variable.captures[0].range, 21..28);
+        assert_eq!(variable.captures[0].details, ["FOO = \"bar\""]);
+    }
+
+    /// Arguments of the first attribute in a Rust source.
+    fn attribute_args(source: &str) -> Vec<AttributeArgument> {
+        let language = tree_sitter_rust::language();
+        let tree = parse(language, source);
+        let query = Query::new(language, "(attribute) @attr").unwrap();
+        let mut cursor = QueryCursor::new();
+        let matched = cursor
+            .matches(&query, tree.root_node(), source.as_bytes())
+            .next()
+            .unwrap();
+        parse_attribute_args(matched.captures[0].node, source.as_bytes())
+    }
+
+    fn argument(key: &str, kind: &str, value: &str) -> AttributeArgument {
+        AttributeArgument {
+            key: key.to_string(),
+            kind: kind.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn attribute_args_with_escapes_commas_and_parens() {
+        let source = r#"#[rawr(
+    rev = "abc123",
+    notes = "It's \"done\", (mostly), see f(a, b)",
+    priority = 2,
+)]
+fn foo() {}
+"#;
+        let args = attribute_args(source);
+        assert_eq!(
+            args,
+            [
+                argument("rev", "string_literal", r#""abc123""#),
+                argument(
+                    "notes",
+                    "string_literal",
+                    r#""It's \"done\", (mostly), see f(a, b)""#
+                ),
+                argument("priority", "integer_literal", "2"),
+            ]
+        );
+        assert_eq!(
+            args[1].string_value().unwrap(),
+            r#"It's "done", (mostly), see f(a, b)"#
+        );
+        assert_eq!(args[2].string_value(), None);
+    }
+
+    #[test]
+    fn attribute_args_skip_malformed() {
+        let args = attribute_args("#[rawr(a = 1, broken, b = 2 3, c = \"x\",,)]\nstruct S;\n");
+        assert_eq!(
+            args,
+            [
+                argument("a", "integer_literal", "1"),
+                argument("c", "string_literal", "\"x\""),
+            ]
+        );
+        assert!(attribute_args("#[rawr]\nstruct S;\n").is_empty());
+    }
+
+    #[test]
+    fn unescape_raw_and_multi_line_strings() {
+        // Raw strings are taken as written, minus their delimiters.
+        assert_eq!(
+            unescape_string_literal(r####"r#"say "hi" \n C:\dir"#"####).unwrap(),
+            r#"say "hi" \n C:\dir"#
+        );
+        assert_eq!(unescape_string_literal(r#"r"plain""#).unwrap(), "plain");
+        assert_eq!(
+            unescape_string_literal(r####"r##"a "# b"##"####).unwrap(),
+            r##"a "# b"##
+        );
+        assert_eq!(unescape_string_literal(r####"r#"unterminated""####), None);
+
+        // Newlines are kept, while continuations drop the following indent.
+        assert_eq!(
+            unescape_string_literal("\"first\n  second\"").unwrap(),
+            "first\n  second"
+        );
+        assert_eq!(
+            unescape_string_literal("\"first \\\n    second\"").unwrap(),
+            "first second"
+        );
+        assert_eq!(
+            unescape_string_literal(r#""tab\t\x41\u{1F600}\\""#).unwrap(),
+            "tab\tA\u{1F600}\\"
+        );
+        assert_eq!(unescape_string_literal(r#""bad \q""#), None);
+    }
+
+    #[test]
+    fn raw_and_multi_line_attribute_values() {
+        let source = r####"#[rawr(
+    notes = r#"Handles "quoted" C:\paths\ as-is"#,
+    summary = "line one
+line two",
+)]
+fn foo() {}
+"####;
+        let args = attribute_args(source);
+        assert_eq!(args.len(), 2);
+        assert_eq!(args[0].kind, "raw_string_literal");
+        assert_eq!(
+            args[0].string_value().unwrap(),
+            r#"Handles "quoted" C:\paths\ as-is"#
+        );
+        assert_eq!(args[1].string_value().unwrap(), "line one\nline two");
+    }
+}
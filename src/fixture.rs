@@ -0,0 +1,111 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Throwaway git repositories for tests. Built for unit tests, and for
+//! integration tests with the `test-fixtures` feature.
+
+use gix::ObjectId;
+use std::cell::Cell;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+/// Git repository in the system temp directory, removed on drop. Commits are a
+/// second apart so that their order is unambiguous.
+pub struct Fixture {
+    pub dir: PathBuf,
+    commits: Cell<i64>,
+}
+
+impl Fixture {
+    pub fn new() -> Self {
+        let dir = std::env::temp_dir().join(format!("rawr-test-{:016x}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let fixture = Fixture {
+            dir,
+            commits: Cell::new(0),
+        };
+        fixture.git(&["init", "-q"]);
+        fixture
+    }
+
+    /// Run git in the repository, returning its trimmed output.
+    pub fn git(&self, args: &[&str]) -> String {
+        let date = format!("{} +0000", 1_700_000_000 + self.commits.get());
+        let output = Command::new("git")
+            .args(["-c", "user.name=rawr", "-c", "user.email=rawr@example.com"])
+            .args(["-c", "commit.gpgsign=false"])
+            .args(args)
+            .env("GIT_AUTHOR_DATE", &date)
+            .env("GIT_COMMITTER_DATE", &date)
+            .current_dir(&self.dir)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "git {args:?}: {output:?}");
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    }
+
+    /// Write files and commit them on top of the previous commit.
+    pub fn commit(&self, files: &[(&str, &[u8])]) -> ObjectId {
+        for (path, contents) in files {
+            let path = self.dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+        self.commits.set(self.commits.get() + 1);
+        self.git(&["add", "-A"]);
+        self.git(&["commit", "-q", "--allow-empty", "-m", "fixture"]);
+        self.head()
+    }
+
+    /// Merge a branch into the current one with a merge commit.
+    pub fn merge(&self, branch: &str) -> ObjectId {
+        self.commits.set(self.commits.get() + 1);
+        self.git(&["merge", "-q", "--no-ff", "-m", "merge", branch]);
+        self.head()
+    }
+
+    pub fn head(&self) -> ObjectId {
+        ObjectId::from_hex(self.git(&["rev-parse", "HEAD"]).as_bytes()).unwrap()
+    }
+
+    pub fn repo(&self) -> gix::Repository {
+        gix::open(&self.dir).unwrap()
+    }
+
+    /// Run a binary from within the repository, failing on a non-zero exit.
+    pub fn run(&self, binary: &str, args: &[&str]) -> String {
+        let output = self.run_output(binary, args);
+        assert!(output.status.success(), "{binary} {args:?}: {output:?}");
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    pub fn run_output(&self, binary: &str, args: &[&str]) -> Output {
+        Command::new(binary)
+            .args(args)
+            .current_dir(&self.dir)
+            .output()
+            .unwrap()
+    }
+
+    pub fn path(&self, path: &str) -> PathBuf {
+        self.dir.join(path)
+    }
+}
+
+impl Default for Fixture {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Rows stored in a database.
+pub fn row_count(database: &Path) -> i64 {
+    let conn = crate::db::connect_ro(database).unwrap();
+    conn.query_row("SELECT COUNT(*) FROM interesting", [], |row| row.get(0))
+        .unwrap()
+}
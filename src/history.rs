@@ -0,0 +1,227 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Find the revisions where a tracked item changed, using previously-scanned
+//! matches from the database.
+
+use crate::db::{read_hash, read_optional_hash};
+use crate::Hash;
+use rusqlite::{params, Connection, OptionalExtension};
+
+/// How an item differs from its previous revision.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Change {
+    /// Item appeared, either for the first time or after being deleted.
+    Add,
+    /// Item's contents changed.
+    Modify,
+    /// Only the item's whitespace changed, such as from reformatting.
+    Whitespace,
+    /// Item disappeared.
+    Delete,
+}
+
+/// List the revisions where an item was added, modified, or deleted. Revisions
+/// must be ordered oldest first, and should already be scanned into the
+/// database. Unscanned revisions look like deletions.
+// DESIGN Whitespace runs are collapsed rather than removed, so spacing added
+// between tokens that had none counts as a modification. Rows scanned before
+// normalized hashes were stored can't be compared, and count as modified.
+pub fn changes_between(
+    conn: &Connection,
+    revisions: &[String],
+    codebase: &str,
+    path: &str,
+    ancestors: &str,
+    kind: &str,
+    identifier: &str,
+) -> anyhow::Result<Vec<(String, Change)>> {
+    // Ambiguous items are not supported, so only the first match is considered.
+    let mut statement = conn.prepare(
+        "SELECT hash_algorithm, hash, normalized_hash FROM interesting
+        WHERE codebase = ? AND revision = ? AND path = ? AND kind = ? AND identifier = ?
+            -- Rows scanned before ancestors were tracked have none.
+            AND (ancestors = ? OR ancestors IS NULL)
+        ORDER BY start_byte
+        LIMIT 1",
+    )?;
+
+    let mut changes = Vec::new();
+    let mut previous: Option<(Hash, Option<Hash>)> = None;
+    for revision in revisions {
+        let hashes = statement
+            .query_row(
+                params![codebase, revision, path, kind, identifier, ancestors],
+                |row| Ok((read_hash(row, 0, 1)?, read_optional_hash(row, 0, 2)?)),
+            )
+            .optional()?;
+
+        let change = match (&previous, &hashes) {
+            (None, Some(_)) => Some(Change::Add),
+            (Some(_), None) => Some(Change::Delete),
+            (Some((before, _)), Some((after, _))) if before == after => None,
+            (Some((_, Some(before))), Some((_, Some(after)))) if before == after => {
+                Some(Change::Whitespace)
+            }
+            (Some(_), Some(_)) => Some(Change::Modify),
+            (None, None) => None,
+        };
+
+        if let Some(change) = change {
+            changes.push((revision.to_string(), change));
+        }
+        previous = hashes;
+    }
+
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{connect_memory, insert_batch};
+    use crate::Interesting;
+
+    fn item(revision: &str, hash: u8) -> Interesting {
+        Interesting {
+            codebase: "codebase".to_string(),
+            revision: revision.to_string(),
+            committed_at: None,
+            path: "src/lib.rs".to_string(),
+            ancestors: String::new(),
+            start_byte: Some(0),
+            length: Some(10),
+            kind: "function".to_string(),
+            identifier: "draw".to_string(),
+            salt: 0,
+            hash: Hash::Sha256([hash; 32]),
+            normalized_hash: Hash::Sha256([hash; 32]),
+            notes: None,
+        }
+    }
+
+    #[test]
+    fn changes_over_timeline() {
+        // Added in r1, unchanged in r2, modified in r3, deleted in r4, and
+        // restored in r5.
+        let mut conn = connect_memory().unwrap();
+        let timeline = [item("r1", 1), item("r2", 1), item("r3", 2), item("r5", 2)];
+        insert_batch(&mut conn, &timeline).unwrap();
+
+        let revisions = ["r1", "r2", "r3", "r4", "r5"].map(String::from);
+        let changes = changes_between(
+            &conn,
+            &revisions,
+            "codebase",
+            "src/lib.rs",
+            "",
+            "function",
+            "draw",
+        )
+        .unwrap();
+        assert_eq!(
+            changes,
+            [
+                ("r1".to_string(), Change::Add),
+                ("r3".to_string(), Change::Modify),
+                ("r4".to_string(), Change::Delete),
+                ("r5".to_string(), Change::Add),
+            ]
+        );
+    }
+
+    #[test]
+    fn whitespace_changes_are_distinguished() {
+        // Reformatted in r2, and reformatted again in r3 before normalized
+        // hashes were stored.
+        let mut conn = connect_memory().unwrap();
+        let reformatted = |revision, hash| Interesting {
+            normalized_hash: Hash::Sha256([1; 32]),
+            ..item(revision, hash)
+        };
+        let timeline = [item("r1", 1), reformatted("r2", 2), reformatted("r3", 3)];
+        insert_batch(&mut conn, &timeline).unwrap();
+        conn.execute(
+            "UPDATE interesting SET normalized_hash = NULL WHERE revision = 'r3'",
+            [],
+        )
+        .unwrap();
+
+        let revisions = ["r1", "r2", "r3"].map(String::from);
+        let changes = changes_between(
+            &conn,
+            &revisions,
+            "codebase",
+            "src/lib.rs",
+            "",
+            "function",
+            "draw",
+        )
+        .unwrap();
+        assert_eq!(
+            changes,
+            [
+                ("r1".to_string(), Change::Add),
+                ("r2".to_string(), Change::Whitespace),
+                ("r3".to_string(), Change::Modify),
+            ]
+        );
+    }
+
+    #[test]
+    fn same_name_in_other_scope_is_separate() {
+        // Widget::draw changes in r2, while Gadget::draw only appears there.
+        let mut conn = connect_memory().unwrap();
+        let in_scope = |ancestors: &str, revision, hash, start_byte| Interesting {
+            ancestors: ancestors.to_string(),
+            start_byte: Some(start_byte),
+            ..item(revision, hash)
+        };
+        let timeline = [
+            in_scope("Widget", "r1", 1, 100),
+            in_scope("Gadget", "r2", 3, 0),
+            in_scope("Widget", "r2", 2, 100),
+        ];
+        insert_batch(&mut conn, &timeline).unwrap();
+
+        let revisions = ["r1", "r2"].map(String::from);
+        let changes = |ancestors| {
+            changes_between(
+                &conn,
+                &revisions,
+                "codebase",
+                "src/lib.rs",
+                ancestors,
+                "function",
+                "draw",
+            )
+            .unwrap()
+        };
+        assert_eq!(
+            changes("Widget"),
+            [
+                ("r1".to_string(), Change::Add),
+                ("r2".to_string(), Change::Modify),
+            ]
+        );
+        assert_eq!(changes("Gadget"), [("r2".to_string(), Change::Add)]);
+    }
+
+    #[test]
+    fn no_changes_for_unknown_item() {
+        let mut conn = connect_memory().unwrap();
+        insert_batch(&mut conn, &[item("r1", 1)]).unwrap();
+
+        let revisions = ["r1".to_string()];
+        let changes = changes_between(
+            &conn,
+            &revisions,
+            "codebase",
+            "src/lib.rs",
+            "",
+            "function",
+            "other",
+        )
+        .unwrap();
+        assert!(changes.is_empty());
+    }
+}
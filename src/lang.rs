@@ -1,11 +1,20 @@
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::Hash;
+use anyhow::bail;
+use encoding_rs::Encoding;
 use regex::Regex;
 use serde::de;
 use serde::de::Deserialize;
 use serde::Deserializer;
+use sha2::{Digest, Sha256};
 use std::cell::OnceCell;
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::ops::Range;
+use std::path::Path;
 use std::sync::OnceLock;
+use tree_sitter::{Node, QueryCapture, QueryCursor, QueryMatch, QueryPredicateArg};
 
 #[derive(Debug, Eq, PartialEq, Hash)]
 pub enum SupportedLanguage {
@@ -15,24 +24,196 @@ pub enum SupportedLanguage {
     Cpp,
 }
 
+impl SupportedLanguage {
+    /// Guess the language from a file extension, without the leading dot.
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension {
+            "rs" => Some(SupportedLanguage::Rust),
+            "sh" | "bash" => Some(SupportedLanguage::Bash),
+            "c" | "h" => Some(SupportedLanguage::C),
+            "cc" | "cpp" | "cxx" | "hh" | "hpp" | "hxx" => Some(SupportedLanguage::Cpp),
+            _ => None,
+        }
+    }
+
+    /// Guess the language from the interpreter named on a script's first line.
+    /// Both direct (`#!/bin/bash`) and `env`-style (`#!/usr/bin/env bash`)
+    /// shebangs are recognized.
+    pub fn from_shebang(source_bytes: &[u8]) -> Option<Self> {
+        let line = source_bytes.split(|b| *b == b'\n').next()?;
+        let line = std::str::from_utf8(line.strip_prefix(b"#!")?).ok()?;
+
+        let mut words = line.split_whitespace();
+        let mut interpreter = words.next()?.rsplit('/').next()?;
+
+        // Skip env and its flags to find the real interpreter.
+        if interpreter == "env" {
+            interpreter = words.find(|word| !word.starts_with('-') && !word.contains('='))?;
+        }
+
+        match interpreter {
+            "sh" | "bash" | "dash" | "ksh" | "zsh" => Some(SupportedLanguage::Bash),
+            _ => None,
+        }
+    }
+
+    /// Tree-Sitter grammar for the language.
+    pub fn grammar(&self) -> tree_sitter::Language {
+        match self {
+            SupportedLanguage::Rust => tree_sitter_rust::language(),
+            SupportedLanguage::Bash => tree_sitter_bash::language(),
+            SupportedLanguage::C => tree_sitter_c::language(),
+            SupportedLanguage::Cpp => tree_sitter_cpp::language(),
+        }
+    }
+
+    /// Node kind at the root of every parsed file.
+    pub fn root_kind(&self) -> &'static str {
+        match self {
+            SupportedLanguage::Rust => "source_file",
+            SupportedLanguage::Bash => "program",
+            SupportedLanguage::C | SupportedLanguage::Cpp => "translation_unit",
+        }
+    }
+
+    /// Grammar for the language, checked against the Tree-Sitter runtime's
+    /// supported ABI versions. A mismatched grammar crate would otherwise fail
+    /// later with an unhelpful parser or query error.
+    pub fn compatible_grammar(&self) -> anyhow::Result<tree_sitter::Language> {
+        let grammar = self.grammar();
+        self.check_abi_version(grammar.version())?;
+        Ok(grammar)
+    }
+
+    /// Check a grammar's ABI version against the range the runtime supports.
+    fn check_abi_version(&self, version: usize) -> anyhow::Result<()> {
+        let supported =
+            tree_sitter::MIN_COMPATIBLE_LANGUAGE_VERSION..=tree_sitter::LANGUAGE_VERSION;
+        if !supported.contains(&version) {
+            bail!(
+                "{self:?} grammar uses ABI version {version}, but the Tree-Sitter runtime supports {} to {}",
+                supported.start(),
+                supported.end()
+            );
+        }
+        Ok(())
+    }
+
+    /// Detect a file's language by extension, falling back to the shebang for
+    /// scripts without a recognized extension, such as `deploy` or `deploy.v2`.
+    pub fn detect(path: &Path, source_bytes: &[u8]) -> Option<Self> {
+        path.extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(Self::from_extension)
+            .or_else(|| Self::from_shebang(source_bytes))
+    }
+}
+
+/// Guess whether a file is binary, using Git's heuristic of a NUL byte near the
+/// start of the file.
+pub fn looks_binary(source_bytes: &[u8]) -> bool {
+    const PREFIX_LEN: usize = 8000;
+
+    let prefix = &source_bytes[..source_bytes.len().min(PREFIX_LEN)];
+    prefix.contains(&0)
+}
+
 /// Extract information with a named match in the Tree-Sitter grammar, or use a
 /// new query to extract the node.
 #[derive(Debug, Eq, PartialEq, Hash)]
 pub enum MatchType {
     /// Reuse the entire match
     Match,
+    /// Span of every capture in the match, such as a leading doc comment
+    /// captured alongside its item.
+    Span,
     /// Named child to extract as text.
     Named(String),
     /// The nth child of the grammar's given type.
     Kind(usize, String),
+    /// Named capture from the matcher's query, without the leading `@`.
+    Capture(String),
     /// Use a formatted string in place of a match. The only supported
-    /// substitution is `${file_name}`, however this will likely be switched to
-    /// a templating system.
+    /// substitution is `${file_name}`, the file's canonical relative path as
+    /// produced by `normalize_path`. This will likely be switched to a
+    /// templating system.
     String(String),
-    /// Tree-Sitter query and nth-match from which to extract text.
-    SubQuery(usize, String),
+    /// Tree-Sitter query and nth-match from which to extract text. Takes the
+    /// named capture if given, otherwise the span of the whole match.
+    SubQuery(usize, String, Option<String>),
+    /// Concatenate several extractions, separated by a delimiter.
+    Joined(String, Vec<MatchType>),
+    /// Like `Joined`, but collapses whitespace within each extraction so that
+    /// reformatting doesn't change the result. `String` parts are literal, and
+    /// kept as written.
+    JoinedNormalized(String, Vec<MatchType>),
+}
+
+/// Reasons that a `MatchType` could not extract anything from a match.
+#[derive(Debug, Eq, PartialEq)]
+pub enum ExtractionError {
+    /// Query match has no captures to extract from.
+    NoCaptures,
+    /// Subquery found nothing in the given (abbreviated) text.
+    NoMatches(String),
+    /// Named field or capture is absent from the match.
+    NamedMatchNotFound(String),
+    /// Subquery found fewer matches than the requested index.
+    NumberedMatchNotFound(usize),
+    /// Matched range lies outside the source. The tree and source disagree.
+    MatchBytesOutOfBounds(Range<usize>),
+    /// Subquery does not compile.
+    InvalidQuery(String),
+    /// Extraction isn't implemented yet. `Matcher::validate` rejects these.
+    Unsupported(String),
 }
 
+impl ExtractionError {
+    /// Whether the match should be skipped, rather than the scan aborted.
+    /// Optional parts of an item may legitimately be absent.
+    pub fn is_skippable(&self) -> bool {
+        matches!(
+            self,
+            ExtractionError::NoMatches(_)
+                | ExtractionError::NamedMatchNotFound(_)
+                | ExtractionError::NumberedMatchNotFound(_)
+        )
+    }
+}
+
+impl Display for ExtractionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ExtractionError::NoCaptures => write!(f, "Match has no captures"),
+            ExtractionError::NoMatches(context) => {
+                write!(f, "Subquery matched nothing in: {context}")
+            }
+            ExtractionError::NamedMatchNotFound(name) => write!(f, "No match named {name}"),
+            ExtractionError::NumberedMatchNotFound(index) => write!(f, "No match number {index}"),
+            ExtractionError::MatchBytesOutOfBounds(range) => {
+                write!(f, "Matched bytes {range:?} are outside of the source")
+            }
+            ExtractionError::InvalidQuery(e) => write!(f, "Invalid subquery: {e}"),
+            ExtractionError::Unsupported(name) => write!(f, "{name} extraction is not supported"),
+        }
+    }
+}
+
+impl std::error::Error for ExtractionError {}
+
+/// Names of the `MatchType` variants, for deserialization errors.
+const MATCH_TYPE_VARIANTS: &[&str] = &[
+    "Match",
+    "Span",
+    "Named",
+    "Kind",
+    "Capture",
+    "String",
+    "SubQuery",
+    "Joined",
+    "JoinedNormalized",
+];
+
 /// Deserialize a string containing a MatchType variant.
 impl<'de> Deserialize<'de> for MatchType {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
@@ -42,10 +223,14 @@ impl<'de> Deserialize<'de> for MatchType {
         // Deserialize as whole string
         let s = String::deserialize(deserializer)?;
 
-        // The Match type doesn't take any options. Return if it is specified.
+        // The Match and Span types don't take any options. Return if either is
+        // specified.
         if s == "Match" {
             return Ok(MatchType::Match);
         }
+        if s == "Span" {
+            return Ok(MatchType::Span);
+        }
 
         static VARIANT_REGEX: OnceLock<Regex> = OnceLock::new();
         let variant_regex = VARIANT_REGEX.get_or_init(|| {
@@ -61,32 +246,34 @@ impl<'de> Deserialize<'de> for MatchType {
         };
 
         let Some(variant) = matches.name("variant") else {
-            return Err(de::Error::unknown_variant(
-                "",
-                ["Match", "Named", "Kind", "String", "SubQuery"].as_ref(),
-            ));
+            return Err(de::Error::unknown_variant("", MATCH_TYPE_VARIANTS));
         };
 
+        // TODO Parse the arguments of the remaining variants.
         match variant.as_str() {
-            "Match" => unreachable!("Match was handled early in the function"),
-            "Named" => todo!(),    // String
-            "String" => todo!(),   // String
-            "Kind" => todo!(),     // usize, String
-            "SubQuery" => todo!(), // usize, String
-            _ => {
-                return Err(de::Error::unknown_variant(
-                    "",
-                    &["Match", "Named", "Kind", "String", "SubQuery"],
-                ))
-            }
+            "Match" | "Span" => unreachable!("Unit variants were handled early in the function"),
+            "Named" | "String" | "Kind" | "Capture" | "SubQuery" | "Joined"
+            | "JoinedNormalized" => Err(de::Error::custom(format!(
+                "MatchType {} is not yet supported in configuration",
+                variant.as_str()
+            ))),
+            other => Err(de::Error::unknown_variant(other, MATCH_TYPE_VARIANTS)),
         }
-
-        todo!()
     }
 }
 
+/// Name of the capture holding the matched item when a query has several.
+pub const OUTER_CAPTURE: &str = "outer";
+
 /// Assumes that the interesting parts are actually named in the Tree-Sitter
 /// grammar.
+///
+/// The query's capture named `@outer` is the matched item. Queries with a single
+/// capture may name it anything. Other captures can be used by `Capture`
+/// extractions.
+///
+/// Queries may contain several patterns, such as one per node kind, as long as
+/// each is rooted. Give each pattern's item the same `@outer` capture.
 #[derive(Debug, Eq, PartialEq)]
 pub struct Matcher {
     /// Friendly name for matches
@@ -98,41 +285,462 @@ pub struct Matcher {
     pub identifier: MatchType,
     /// Name of field containing body contents.
     pub contents: MatchType,
+    /// Tree-Sitter query that vetoes a match when it matches anywhere inside
+    /// the matched item, such as test functions or generated code.
+    pub excludes: Option<String>,
     /// Human-readable information about this matcher.
     pub notes: Option<String>,
 }
 
+impl Matcher {
+    /// Check that the compiled query and subqueries can be used by this
+    /// matcher, returning a description of each problem found.
+    pub fn validate(
+        &self,
+        query: &tree_sitter::Query,
+        subqueries: &HashMap<String, tree_sitter::Query>,
+    ) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if query.pattern_count() == 0 {
+            problems.push("Query has no patterns".to_string());
+        }
+
+        if (0..query.pattern_count()).any(|index| !query.is_pattern_rooted(index)) {
+            problems.push("Query patterns must have a single root node".to_string());
+        }
+
+        if query.capture_names().is_empty() {
+            problems.push("Query must capture the matched item".to_string());
+        }
+
+        if query.capture_names().len() > 1 && query.capture_index_for_name(OUTER_CAPTURE).is_none()
+        {
+            problems.push(format!(
+                "Query has several captures, so the matched item must be named @{OUTER_CAPTURE}"
+            ));
+        }
+
+        problems.extend(predicate_problems(query));
+
+        for match_type in [&self.identifier, &self.contents] {
+            for name in match_type.capture_names() {
+                if query.capture_index_for_name(name).is_none() {
+                    problems.push(format!("Query has no capture named @{name}"));
+                }
+            }
+
+            for name in match_type.unsupported() {
+                problems.push(format!("{name} extraction is not supported"));
+            }
+
+            for source in match_type.subqueries() {
+                let Some(subquery) = subqueries.get(source) else {
+                    continue;
+                };
+                problems.extend(
+                    predicate_problems(subquery)
+                        .into_iter()
+                        .map(|problem| format!("Subquery {source}: {problem}")),
+                );
+            }
+        }
+
+        problems
+    }
+}
+
+/// Predicates in a query that `satisfies_predicates` can't check.
+fn predicate_problems(query: &tree_sitter::Query) -> Vec<String> {
+    let mut problems = Vec::new();
+    for pattern_index in 0..query.pattern_count() {
+        for predicate in query.general_predicates(pattern_index) {
+            let operator = predicate.operator.as_ref();
+            if !GENERAL_PREDICATES.contains(&operator) {
+                problems.push(format!("Unsupported predicate #{operator}"));
+            } else if !matches!(predicate.args.first(), Some(QueryPredicateArg::Capture(_))) {
+                problems.push(format!("Predicate #{operator} must start with a capture"));
+            }
+        }
+    }
+    problems
+}
+
+/// Predicates evaluated by `satisfies_predicates`. Tree-Sitter evaluates
+/// `#eq?`, `#match?`, and their negations itself while matching.
+const GENERAL_PREDICATES: &[&str] = &["any-of?", "not-any-of?"];
+
+/// Check the predicates that Tree-Sitter leaves to the caller, such as
+/// `(#any-of? @name "new" "default")`. Unsupported predicates are rejected by
+/// `Matcher::validate`, so they are treated as satisfied here.
+pub fn satisfies_predicates(
+    query: &tree_sitter::Query,
+    matched: &QueryMatch,
+    source_bytes: &[u8],
+) -> bool {
+    query
+        .general_predicates(matched.pattern_index)
+        .iter()
+        .all(|predicate| {
+            let Some((QueryPredicateArg::Capture(index), values)) = predicate.args.split_first()
+            else {
+                return true;
+            };
+            let negated = match predicate.operator.as_ref() {
+                "any-of?" => false,
+                "not-any-of?" => true,
+                _ => return true,
+            };
+
+            matched
+                .captures
+                .iter()
+                .filter(|cap| cap.index == *index)
+                .all(|cap| {
+                    let text = source_bytes.get(cap.node.byte_range());
+                    let found = values.iter().any(|value| match value {
+                        QueryPredicateArg::String(value) => Some(value.as_bytes()) == text,
+                        QueryPredicateArg::Capture(_) => false,
+                    });
+                    found != negated
+                })
+        })
+}
+
+/// Find the capture holding the matched item: `@outer` if the query has one,
+/// otherwise the first capture.
+pub fn outer_capture<'cursor, 'tree>(
+    query: &tree_sitter::Query,
+    matched: &QueryMatch<'cursor, 'tree>,
+) -> Option<&'cursor QueryCapture<'tree>> {
+    match query.capture_index_for_name(OUTER_CAPTURE) {
+        Some(index) => matched.captures.iter().find(|cap| cap.index == index),
+        None => matched.captures.first(),
+    }
+}
+
+/// Separator between names in an ancestor path.
+pub const ANCESTOR_SEPARATOR: &str = "/";
+
+/// Names of the items enclosing a node, outermost first and joined with `/`,
+/// such as `outer/Widget/draw`. Ancestors are named by their `name` field, or
+/// `type` for impl blocks. Empty for top-level items.
+pub fn ancestor_path(node: Node, source_bytes: &[u8]) -> String {
+    let mut names = Vec::new();
+    let mut parent = node.parent();
+    while let Some(ancestor) = parent {
+        let name = ancestor
+            .child_by_field_name("name")
+            .or_else(|| ancestor.child_by_field_name("type"));
+        if let Some(text) = name.and_then(|name| name.utf8_text(source_bytes).ok()) {
+            names.push(text);
+        }
+        parent = ancestor.parent();
+    }
+
+    names.reverse();
+    names.join(ANCESTOR_SEPARATOR)
+}
+
+/// Collapse runs of whitespace to a single space, and trim the ends.
+pub fn normalize_whitespace(bytes: &[u8]) -> Vec<u8> {
+    bytes
+        .split(|b| b.is_ascii_whitespace())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(&b' ')
+}
+
+impl MatchType {
+    /// Names of all query captures referenced by this extraction.
+    pub fn capture_names(&self) -> Vec<&str> {
+        match self {
+            MatchType::Capture(name) => vec![name.as_str()],
+            MatchType::Joined(_, parts) | MatchType::JoinedNormalized(_, parts) => {
+                parts.iter().flat_map(|part| part.capture_names()).collect()
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Extractions used here that aren't implemented yet.
+    pub fn unsupported(&self) -> Vec<&'static str> {
+        match self {
+            MatchType::Kind(..) => vec!["Kind"],
+            MatchType::Joined(_, parts) | MatchType::JoinedNormalized(_, parts) => {
+                parts.iter().flat_map(|part| part.unsupported()).collect()
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Sources of all subqueries run by this extraction.
+    pub fn subqueries(&self) -> Vec<&str> {
+        match self {
+            MatchType::SubQuery(_, query, _) => vec![query.as_str()],
+            MatchType::Joined(_, parts) | MatchType::JoinedNormalized(_, parts) => {
+                parts.iter().flat_map(|part| part.subqueries()).collect()
+            }
+            _ => vec![],
+        }
+    }
+}
+
 pub enum Query {
     TreeSitter(String),
     Constant,
 }
 
+/// Matcher with its query compiled against a particular grammar.
+pub struct CompiledMatcher {
+    pub matcher: Matcher,
+    pub query: tree_sitter::Query,
+    pub excludes: Option<tree_sitter::Query>,
+    /// Queries of `SubQuery` extractions, keyed by their source.
+    pub subqueries: HashMap<String, tree_sitter::Query>,
+}
+
+impl CompiledMatcher {
+    /// Check whether the exclusion query matches anywhere inside a match.
+    pub fn is_excluded(
+        &self,
+        matched: &QueryMatch,
+        source_bytes: &[u8],
+        cursor: &mut QueryCursor,
+    ) -> bool {
+        let (Some(excludes), Some(outer)) = (&self.excludes, outer_capture(&self.query, matched))
+        else {
+            return false;
+        };
+
+        let mut found = cursor.matches(excludes, outer.node, source_bytes);
+        found.any(|matched| satisfies_predicates(excludes, &matched, source_bytes))
+    }
+}
+
+/// A language's grammar and matchers. Queries are compiled once up front, rather
+/// than for every file.
+pub struct Dialect {
+    pub language: SupportedLanguage,
+    pub grammar: tree_sitter::Language,
+    pub matchers: Vec<CompiledMatcher>,
+    /// Encoding of source files, if not UTF-8. Files are transcoded to UTF-8
+    /// for parsing, and offsets are mapped back to the original bytes.
+    pub encoding: Option<&'static Encoding>,
+}
+
+impl Dialect {
+    pub fn new(language: SupportedLanguage, matchers: Vec<Matcher>) -> anyhow::Result<Self> {
+        let grammar = language.compatible_grammar()?;
+
+        // Report every broken matcher at once, rather than one per attempt.
+        let mut problems = Vec::new();
+        let mut compiled = Vec::with_capacity(matchers.len());
+        for matcher in matchers {
+            let query = match tree_sitter::Query::new(grammar, matcher.query.as_str()) {
+                Ok(query) => query,
+                Err(e) => {
+                    problems.push(format!("{}: {}", matcher.kind, e));
+                    continue;
+                }
+            };
+
+            let excludes = match &matcher.excludes {
+                Some(excludes) => match tree_sitter::Query::new(grammar, excludes.as_str()) {
+                    Ok(excludes) => Some(excludes),
+                    Err(e) => {
+                        problems.push(format!("{} excludes: {}", matcher.kind, e));
+                        continue;
+                    }
+                },
+                None => None,
+            };
+
+            let mut subqueries = HashMap::new();
+            for match_type in [&matcher.identifier, &matcher.contents] {
+                for source in match_type.subqueries() {
+                    match tree_sitter::Query::new(grammar, source) {
+                        Ok(subquery) => {
+                            subqueries.insert(source.to_string(), subquery);
+                        }
+                        Err(e) => problems.push(format!("{} subquery: {}", matcher.kind, e)),
+                    }
+                }
+            }
+
+            problems.extend(
+                matcher
+                    .validate(&query, &subqueries)
+                    .into_iter()
+                    .map(|problem| format!("{}: {}", matcher.kind, problem)),
+            );
+            compiled.push(CompiledMatcher {
+                matcher,
+                query,
+                excludes,
+                subqueries,
+            });
+        }
+
+        if !problems.is_empty() {
+            bail!(
+                "Invalid {:?} matchers:\n\t{}",
+                language,
+                problems.join("\n\t")
+            );
+        }
+
+        Ok(Dialect {
+            language,
+            grammar,
+            matchers: compiled,
+            encoding: None,
+        })
+    }
+
+    pub fn rust() -> anyhow::Result<Self> {
+        Self::new(SupportedLanguage::Rust, matchers_rust())
+    }
+
+    pub fn bash() -> anyhow::Result<Self> {
+        Self::new(SupportedLanguage::Bash, matchers_bash())
+    }
+
+    /// Content hash of the language and matcher definitions. Results cached
+    /// under one version are stale under any other, so store it alongside them.
+    // DESIGN Hashes the Debug rendering, which covers every field but isn't
+    // guaranteed stable across compiler versions. A spurious change only costs
+    // a rescan.
+    pub fn version(&self) -> Hash {
+        let mut digest = Sha256::new();
+        digest.update(format!("{:?}", self.language));
+        digest.update(self.encoding.map_or("UTF-8", Encoding::name));
+        for compiled in &self.matchers {
+            digest.update(format!("{:?}", compiled.matcher));
+        }
+        Hash::Sha256(digest.finalize().into())
+    }
+
+    /// Also match each whole file, identified by a template such as
+    /// `${file_name}`. Off by default, as hashes of entire large files are
+    /// rarely useful, while for small config-like files they are exactly right.
+    pub fn with_whole_file(self, identifier: &str) -> anyhow::Result<Self> {
+        let Dialect {
+            language,
+            matchers,
+            encoding,
+            ..
+        } = self;
+        let mut matchers = matchers
+            .into_iter()
+            .map(|compiled| compiled.matcher)
+            .collect::<Vec<_>>();
+        matchers.insert(0, whole_file_matcher(&language, identifier));
+        Ok(Self::new(language, matchers)?.with_encoding(encoding))
+    }
+
+    /// Read source files in the given encoding, such as Latin-1 or Shift_JIS,
+    /// rather than UTF-8. Hashes are of the transcoded UTF-8 contents.
+    pub fn with_encoding(mut self, encoding: Option<&'static Encoding>) -> Self {
+        // UTF-8 needs no transcoding.
+        self.encoding = encoding.filter(|encoding| *encoding != encoding_rs::UTF_8);
+        self
+    }
+
+    /// Drop every matcher of the given kind, returning how many were removed.
+    /// Kinds are case-sensitive, as they are stored verbatim.
+    pub fn disable(&mut self, kind: &str) -> usize {
+        let before = self.matchers.len();
+        self.matchers
+            .retain(|compiled| compiled.matcher.kind != kind);
+        before - self.matchers.len()
+    }
+}
+
+/// Matcher for the entire contents of a file, with an identifier template
+/// such as `${file_name}`. Files have no natural name, so in every language
+/// they are identified by their canonical relative path unless configured
+/// otherwise.
+pub fn whole_file_matcher(language: &SupportedLanguage, identifier: &str) -> Matcher {
+    Matcher {
+        kind: "file".to_string(),
+        query: format!("(({}) @f)", language.root_kind()),
+        identifier: MatchType::String(identifier.to_string()),
+        contents: MatchType::Match,
+        excludes: None,
+        notes: Some("Exact contents of entire file".to_string()),
+    }
+}
+
 /// Build list of items that should be matched for Rust.
 pub fn matchers_rust() -> Vec<Matcher> {
     use MatchType::*;
     vec![
-        Matcher {
-            kind: "file".to_string(),
-            query: "((source_file) @f)".to_string(),
-            identifier: String("${file_name}".to_string()),
-            contents: Match,
-            notes: Some("Exact contents of entire file".to_string()),
-        },
         Matcher {
             kind: "function".to_string(),
-            query: "((function_item) @fi)".to_string(),
+            // Methods are matched separately, so only take functions at the
+            // top level of a file or module.
+            query: "
+            (source_file (function_item) @fi)
+            (mod_item body: (declaration_list (function_item) @fi))"
+                .to_string(),
             identifier: Named("name".to_string()),
             contents: Match,
+            excludes: None,
             notes: Some(
                 "Function, including visibility, name, parameters, return type, and body "
                     .to_string(),
             ),
         },
+        Matcher {
+            kind: "method".to_string(),
+            query: "
+            ((impl_item
+                !trait
+                type: (_) @type
+                body: (declaration_list
+                    (function_item name: (identifier) @name) @outer)))"
+                .to_string(),
+            identifier: JoinedNormalized(
+                "::".to_string(),
+                vec![Capture("type".to_string()), Capture("name".to_string())],
+            ),
+            contents: Match,
+            excludes: None,
+            notes: Some("Method in an inherent impl, identified as Type::method".to_string()),
+        },
+        Matcher {
+            kind: "method".to_string(),
+            query: "
+            ((impl_item
+                trait: (_) @trait
+                type: (_) @type
+                body: (declaration_list
+                    (function_item name: (identifier) @name) @outer)))"
+                .to_string(),
+            identifier: JoinedNormalized(
+                "".to_string(),
+                vec![
+                    String("<".to_string()),
+                    Capture("type".to_string()),
+                    String(" as ".to_string()),
+                    Capture("trait".to_string()),
+                    String(">::".to_string()),
+                    Capture("name".to_string()),
+                ],
+            ),
+            contents: Match,
+            excludes: None,
+            notes: Some(
+                "Method in a trait impl, identified as <Type as Trait>::method".to_string(),
+            ),
+        },
         Matcher {
             kind: "struct".to_string(),
             query: "((struct_item) @si)".to_string(),
             identifier: Named("name".to_string()),
             contents: Match,
+            excludes: None,
             notes: None,
         },
         Matcher {
@@ -141,6 +749,7 @@ pub fn matchers_rust() -> Vec<Matcher> {
             identifier: Named("name".to_string()),
             // Should be the entire match, or possibly just the type and value.
             contents: Named("value".to_string()),
+            excludes: None,
             notes: None,
         },
         Matcher {
@@ -148,8 +757,27 @@ pub fn matchers_rust() -> Vec<Matcher> {
             query: "((enum_item) @ei)".to_string(),
             identifier: Named("name".to_string()),
             contents: Named("body".to_string()),
+            excludes: None,
             notes: None,
         },
+        Matcher {
+            kind: "trait".to_string(),
+            query: "((trait_item) @ti)".to_string(),
+            identifier: Named("name".to_string()),
+            // Adding, removing, or changing a method signature or default
+            // implementation changes the hash of the whole trait.
+            contents: Match,
+            excludes: None,
+            notes: Some("Trait, including method signatures and default methods".to_string()),
+        },
+        Matcher {
+            kind: "macro".to_string(),
+            query: "((macro_definition) @md)".to_string(),
+            identifier: Named("name".to_string()),
+            contents: Match,
+            excludes: None,
+            notes: Some("Declarative macro_rules! definition".to_string()),
+        },
     ]
 }
 
@@ -159,17 +787,469 @@ pub fn matchers_bash() -> Vec<Matcher> {
     vec![
         Matcher {
             kind: "Variable".to_string(),
-            query: "(variable_assignment)".to_string(),
+            query: "((variable_assignment) @va)".to_string(),
             identifier: Named("name".to_string()),
             contents: Named("value".to_string()),
+            excludes: None,
             notes: None,
         },
         Matcher {
             kind: "Function".to_string(),
-            query: "(function_definition)".to_string(),
+            query: "((function_definition) @fd)".to_string(),
             identifier: Named("name".to_string()),
             contents: Named("body".to_string()),
+            excludes: None,
             notes: None,
         },
     ]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_extensionless_shebang() {
+        let script = b"#!/bin/bash\nFOO=1\n";
+        assert_eq!(
+            SupportedLanguage::detect(Path::new("deploy"), script),
+            Some(SupportedLanguage::Bash)
+        );
+        assert_eq!(
+            SupportedLanguage::detect(Path::new("scripts/deploy.v2"), script),
+            Some(SupportedLanguage::Bash)
+        );
+        assert_eq!(
+            SupportedLanguage::detect(Path::new("run"), b"#!/usr/bin/env -S bash -e\n"),
+            Some(SupportedLanguage::Bash)
+        );
+    }
+
+    #[test]
+    fn detect_prefers_extension() {
+        assert_eq!(
+            SupportedLanguage::detect(Path::new("main.rs"), b"#!/bin/bash\n"),
+            Some(SupportedLanguage::Rust)
+        );
+        assert_eq!(
+            SupportedLanguage::detect(Path::new("notes.txt"), b"text"),
+            None
+        );
+        assert_eq!(
+            SupportedLanguage::detect(Path::new("README"), b"#!/usr/bin/python\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn extensionless_script_is_scanned() {
+        let dialect = Dialect::bash().unwrap();
+        let script = b"#!/bin/bash\nFOO=1\n";
+        let language = SupportedLanguage::detect(Path::new("deploy"), script).unwrap();
+        assert_eq!(language, dialect.language);
+
+        let matches = crate::scan::find_matches_at(&dialect, "deploy", script);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].identifier, "FOO");
+    }
+
+    #[test]
+    fn methods_in_two_impls_are_distinct() {
+        let source = b"struct Foo;
+struct Bar;
+
+impl Foo {
+    fn new() -> Self { Foo }
+}
+
+impl Bar {
+    fn new() -> Self { Bar }
+}
+
+fn new() {}
+";
+        let dialect = Dialect::rust().unwrap();
+        let matches = find_matches(&dialect, source);
+
+        let functions = matches
+            .iter()
+            .filter(|item| item.kind == "method" || item.kind == "function")
+            .map(|item| {
+                (
+                    item.kind.as_str(),
+                    item.identifier.as_str(),
+                    item.ancestors.as_str(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            functions,
+            [
+                ("method", "Foo::new", "Foo"),
+                ("method", "Bar::new", "Bar"),
+                ("function", "new", ""),
+            ]
+        );
+
+        // Each method covers only itself, not its whole impl block.
+        let source = std::str::from_utf8(source).unwrap();
+        for item in matches.iter().filter(|item| item.kind == "method") {
+            let start = item.start_byte.unwrap();
+            let text = &source[start..start + item.length.unwrap()];
+            assert!(text.starts_with("fn new()"), "{text}");
+        }
+        assert!(crate::duplicate_ranges(&matches).is_empty());
+    }
+
+    fn parse_match_type(s: &str) -> Result<MatchType, de::value::Error> {
+        MatchType::deserialize(de::IntoDeserializer::into_deserializer(s))
+    }
+
+    #[test]
+    fn deserialize_match_type() {
+        assert_eq!(parse_match_type("Match").unwrap(), MatchType::Match);
+        assert_eq!(parse_match_type("Span").unwrap(), MatchType::Span);
+
+        let unsupported = parse_match_type("Named(name)").unwrap_err();
+        assert!(unsupported.to_string().contains("not yet supported"));
+        assert!(parse_match_type("Unknown(x)").is_err());
+        assert!(parse_match_type("not a variant").is_err());
+    }
+
+    #[test]
+    fn trait_and_macro_matchers() {
+        let dialect = Dialect::rust().unwrap();
+        for kind in ["trait", "macro"] {
+            let compiled = dialect
+                .matchers
+                .iter()
+                .find(|compiled| compiled.matcher.kind == kind)
+                .unwrap();
+            assert!(compiled
+                .matcher
+                .validate(&compiled.query, &compiled.subqueries)
+                .is_empty());
+        }
+
+        let source = b"trait Shape {
+    fn area(&self) -> f64;
+    fn name(&self) -> &str { \"shape\" }
+}
+
+macro_rules! square {
+    ($x:expr) => { $x * $x };
+}
+";
+        let matches = find_matches(&dialect, source);
+
+        let found = matches
+            .iter()
+            .map(|item| (item.kind.as_str(), item.identifier.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(found, [("trait", "Shape"), ("macro", "square")]);
+
+        // The trait covers its method signatures and default methods.
+        let text = std::str::from_utf8(source).unwrap();
+        assert_eq!(matches[0].start_byte, Some(0));
+        assert_eq!(matches[0].length, Some(text.find("\n}\n").unwrap() + 2));
+    }
+
+    fn matcher(kind: &str, query: &str) -> Matcher {
+        Matcher {
+            kind: kind.to_string(),
+            query: query.to_string(),
+            identifier: MatchType::Named("name".to_string()),
+            contents: MatchType::Match,
+            excludes: None,
+            notes: None,
+        }
+    }
+
+    #[test]
+    fn dialect_reports_every_invalid_matcher() {
+        let matchers = vec![
+            matcher("function", "((function_item) @fi)"),
+            matcher("broken", "((function_item) @fi"),
+            matcher("captureless", "(struct_item)"),
+        ];
+        let Err(e) = Dialect::new(SupportedLanguage::Rust, matchers) else {
+            panic!("Invalid matchers were accepted");
+        };
+
+        let message = e.to_string();
+        assert!(message.contains("Invalid Rust matchers"), "{message}");
+        assert!(message.contains("broken: "), "{message}");
+        assert!(
+            message.contains("captureless: Query must capture the matched item"),
+            "{message}"
+        );
+        assert!(!message.contains("function: "), "{message}");
+    }
+
+    fn validate(matcher: &Matcher) -> Vec<String> {
+        let grammar = SupportedLanguage::Rust.grammar();
+        let query = tree_sitter::Query::new(grammar, &matcher.query).unwrap();
+        let subqueries = [&matcher.identifier, &matcher.contents]
+            .into_iter()
+            .flat_map(MatchType::subqueries)
+            .map(|source| {
+                let subquery = tree_sitter::Query::new(grammar, source).unwrap();
+                (source.to_string(), subquery)
+            })
+            .collect();
+        matcher.validate(&query, &subqueries)
+    }
+
+    #[test]
+    fn grammar_abi_version_must_be_supported() {
+        let min = tree_sitter::MIN_COMPATIBLE_LANGUAGE_VERSION;
+        let max = tree_sitter::LANGUAGE_VERSION;
+        let language = SupportedLanguage::Rust;
+
+        assert!(language.check_abi_version(min).is_ok());
+        assert!(language.check_abi_version(max).is_ok());
+        for version in [min - 1, max + 1] {
+            let Err(e) = language.check_abi_version(version) else {
+                panic!("ABI version {version} was accepted");
+            };
+            assert_eq!(
+                e.to_string(),
+                format!(
+                    "Rust grammar uses ABI version {version}, but the Tree-Sitter runtime supports {min} to {max}"
+                )
+            );
+        }
+
+        // The bundled grammars are all compatible.
+        for language in [
+            SupportedLanguage::Rust,
+            SupportedLanguage::Bash,
+            SupportedLanguage::C,
+            SupportedLanguage::Cpp,
+        ] {
+            assert!(language.compatible_grammar().is_ok(), "{language:?}");
+        }
+    }
+
+    #[test]
+    fn kind_extraction_is_refused() {
+        let mut kind = matcher("function", "((function_item) @fi)");
+        kind.identifier = MatchType::Kind(0, "identifier".to_string());
+        assert_eq!(validate(&kind), ["Kind extraction is not supported"]);
+
+        let mut joined = matcher("function", "((function_item) @fi)");
+        joined.contents = MatchType::Joined(
+            " ".to_string(),
+            vec![MatchType::Match, MatchType::Kind(1, "block".to_string())],
+        );
+        assert_eq!(validate(&joined), ["Kind extraction is not supported"]);
+
+        let Err(e) = Dialect::new(SupportedLanguage::Rust, vec![kind]) else {
+            panic!("Kind matcher was accepted");
+        };
+        assert!(
+            e.to_string()
+                .contains("function: Kind extraction is not supported"),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn validate_captures() {
+        // Extra captures are allowed alongside a designated @outer.
+        let mut valid = matcher(
+            "function",
+            "((function_item name: (identifier) @name body: (_) @body) @outer)",
+        );
+        valid.identifier = MatchType::Capture("name".to_string());
+        valid.contents = MatchType::Capture("body".to_string());
+        assert!(validate(&valid).is_empty());
+
+        // A single capture may have any name.
+        assert!(validate(&matcher("function", "((function_item) @fi)")).is_empty());
+
+        let ambiguous = matcher(
+            "function",
+            "((function_item name: (identifier) @name) @item)",
+        );
+        assert_eq!(
+            validate(&ambiguous),
+            ["Query has several captures, so the matched item must be named @outer"]
+        );
+
+        let mut missing = matcher("function", "((function_item) @outer)");
+        missing.contents = MatchType::Capture("body".to_string());
+        assert_eq!(validate(&missing), ["Query has no capture named @body"]);
+    }
+
+    #[test]
+    fn excludes_veto_only_containing_items() {
+        let excludes = "((macro_invocation macro: (identifier) @m) (#eq? @m \"unimplemented\"))";
+        let mut matchers = matchers_rust();
+        for matcher in &mut matchers {
+            if matcher.kind == "function" || matcher.kind == "method" {
+                matcher.excludes = Some(excludes.to_string());
+            }
+        }
+        let dialect = Dialect::new(SupportedLanguage::Rust, matchers).unwrap();
+
+        let source = b"fn done() {}
+fn stub() { unimplemented!() }
+
+struct Foo;
+impl Foo {
+    fn done(&self) {}
+    fn stub(&self) { unimplemented!() }
+}
+";
+        let matches = find_matches(&dialect, source);
+
+        let identifiers = matches
+            .iter()
+            .filter(|item| item.kind == "function" || item.kind == "method")
+            .map(|item| item.identifier.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(identifiers, ["done", "Foo::done"]);
+    }
+
+    fn find_matches(dialect: &Dialect, source: &[u8]) -> Vec<crate::Interesting> {
+        crate::scan::find_matches_at(dialect, "src/lib.rs", source)
+    }
+
+    #[test]
+    fn joined_normalized_ignores_parameter_whitespace() {
+        let query = "((function_item name: (identifier) @name parameters: (_) @params) @outer)";
+        let parts = || {
+            vec![
+                MatchType::Capture("name".to_string()),
+                MatchType::Capture("params".to_string()),
+            ]
+        };
+        let mut joined = matcher("joined", query);
+        joined.identifier = MatchType::Joined("".to_string(), parts());
+        let mut normalized = matcher("normalized", query);
+        normalized.identifier = MatchType::JoinedNormalized("".to_string(), parts());
+        let dialect = Dialect::new(SupportedLanguage::Rust, vec![joined, normalized]).unwrap();
+
+        // Identifier found by each matcher kind, in kind order.
+        let identifiers = |source: &[u8]| {
+            let mut found = find_matches(&dialect, source)
+                .into_iter()
+                .map(|item| (item.kind, item.identifier))
+                .collect::<Vec<_>>();
+            found.sort();
+            found
+                .into_iter()
+                .map(|(_, identifier)| identifier)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            identifiers(b"fn foo(a: i32, b: i32) {}"),
+            ["foo(a: i32, b: i32)", "foo(a: i32, b: i32)"]
+        );
+        assert_eq!(
+            identifiers(b"fn foo(a: i32,   b: i32) {}"),
+            ["foo(a: i32,   b: i32)", "foo(a: i32, b: i32)"]
+        );
+        assert_eq!(
+            identifiers(b"fn foo(a: i32,\n       b: i32) {}"),
+            ["foo(a: i32,\n       b: i32)", "foo(a: i32, b: i32)"]
+        );
+    }
+
+    #[test]
+    fn trait_method_identifier_keeps_literal_spacing() {
+        let source = b"impl std::fmt::Display for Foo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result { Ok(()) }
+}
+";
+        let matches = find_matches(&Dialect::rust().unwrap(), source);
+        let identifiers = matches
+            .iter()
+            .filter(|item| item.kind == "method")
+            .map(|item| item.identifier.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(identifiers, ["<Foo as std::fmt::Display>::fmt"]);
+    }
+
+    #[test]
+    fn binary_detection() {
+        assert!(looks_binary(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"));
+        assert!(!looks_binary("fn caf\u{e9}() {}".as_bytes()));
+        assert!(!looks_binary(b""));
+
+        // Only the start of the file is inspected, as Git does.
+        let mut late = vec![b'a'; 10_000];
+        late.push(0);
+        assert!(!looks_binary(&late));
+    }
+
+    #[test]
+    fn multiple_patterns_in_one_matcher() {
+        let types = matcher("type", "((struct_item) @outer)\n((enum_item) @outer)");
+        assert!(validate(&types).is_empty());
+
+        // A grouped sequence of siblings has no single root.
+        let unrooted = matcher("type", "((struct_item) @outer (enum_item))");
+        assert!(validate(&unrooted)
+            .contains(&"Query patterns must have a single root node".to_string()));
+
+        let dialect = Dialect::new(SupportedLanguage::Rust, vec![types]).unwrap();
+        let found = find_matches(&dialect, b"struct A;\nenum B {}\nconst C: u8 = 0;\n");
+        let identifiers = found
+            .iter()
+            .map(|item| item.identifier.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(identifiers, ["A", "B"]);
+    }
+
+    #[test]
+    fn disabled_matchers_produce_no_matches() {
+        let source = b"struct A;\nfn b() {}\nconst C: u8 = 0;\n";
+        let mut dialect = Dialect::rust().unwrap();
+        assert_eq!(dialect.disable("struct"), 1);
+        assert_eq!(dialect.disable("struct"), 0);
+        // Kinds are case-sensitive.
+        assert_eq!(dialect.disable("Function"), 0);
+
+        let found = find_matches(&dialect, source);
+        let kinds = found
+            .iter()
+            .map(|item| item.kind.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, ["function", "const"]);
+    }
+
+    #[test]
+    fn predicates_filter_matches() {
+        let source = b"fn test_draw() {}\nfn draw() {}\nfn test_erase() {}\nfn new() {}\n";
+        let identifiers = |predicate: &str| {
+            let query = format!("((function_item name: (identifier) @name) @outer {predicate})");
+            let dialect =
+                Dialect::new(SupportedLanguage::Rust, vec![matcher("function", &query)]).unwrap();
+            find_matches(&dialect, source)
+                .into_iter()
+                .map(|item| item.identifier)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            identifiers("(#match? @name \"^test_\")"),
+            ["test_draw", "test_erase"]
+        );
+        assert_eq!(
+            identifiers("(#not-match? @name \"^test_\")"),
+            ["draw", "new"]
+        );
+        assert_eq!(identifiers("(#eq? @name \"draw\")"), ["draw"]);
+        assert_eq!(
+            identifiers("(#any-of? @name \"new\" \"test_erase\")"),
+            ["test_erase", "new"]
+        );
+        assert_eq!(
+            identifiers("(#not-any-of? @name \"new\" \"test_erase\")"),
+            ["test_draw", "draw"]
+        );
+    }
+}
@@ -1,6 +1,160 @@
 // SPDX-License-Identifier: Apache-2.0
 
+pub mod db;
+pub mod debug;
+#[cfg(any(test, feature = "test-fixtures"))]
+pub mod fixture;
+pub mod history;
 pub mod lang;
+pub mod scan;
+
+use std::ops::Range;
+use std::path::Path;
+use tracing::warn;
+use tree_sitter::QueryMatch;
+
+/// Canonical form of a path, used for storage and lookup. Separators become
+/// forward slashes, empty or `.` segments are dropped, and `..` segments
+/// remove their parent where there is one. `./src//foo.rs` and `src\foo.rs`
+/// both become `src/foo.rs`. Absolute paths keep their leading slash, so use
+/// `root_relative_path` to make a path relative to its codebase.
+pub fn normalize_path(path: &str) -> String {
+    let mut segments = Vec::<&str>::new();
+    for segment in path.split(['/', '\\']) {
+        match segment {
+            "" | "." => {}
+            ".." if segments.last().is_some_and(|last| *last != "..") => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+
+    let normalized = segments.join("/");
+    if path.starts_with(['/', '\\']) {
+        format!("/{normalized}")
+    } else {
+        normalized
+    }
+}
+
+/// Canonical path of a file relative to its codebase's root directory, so that
+/// stored paths don't depend on where a scan was run from. Relative paths are
+/// taken relative to `cwd`. Paths outside the root are rejected. Both
+/// directories should already be canonicalized, as no symlinks are resolved.
+pub fn root_relative_path(root: &Path, cwd: &Path, path: &Path) -> anyhow::Result<String> {
+    let absolute = normalize_path(&cwd.join(path).to_string_lossy());
+    let root = normalize_path(&root.to_string_lossy());
+
+    let relative = match absolute.strip_prefix(&root) {
+        Some(rest) if root.ends_with('/') => Some(rest),
+        Some(rest) => rest.strip_prefix('/'),
+        None => None,
+    };
+    match relative {
+        Some(relative) if !relative.is_empty() => Ok(relative.to_string()),
+        _ => anyhow::bail!("{} is outside of {root}", path.display()),
+    }
+}
+
+/// Byte range covering every capture in a match, from the earliest start to
+/// the latest end. Captures need not be in source order. `None` if the match
+/// has no captures.
+pub fn matched_outer_range(matched: &QueryMatch) -> Option<Range<usize>> {
+    let start = matched
+        .captures
+        .iter()
+        .map(|cap| cap.node.start_byte())
+        .min()?;
+    let end = matched
+        .captures
+        .iter()
+        .map(|cap| cap.node.end_byte())
+        .max()?;
+    Some(start..end)
+}
+
+/// Digest of matched contents, tagged with the algorithm that produced it so
+/// that the two can't disagree. Kept as raw bytes for storage and comparison,
+/// and only rendered as hex for display.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Hash {
+    Sha256([u8; 32]),
+}
+
+impl Hash {
+    /// Algorithm name, as stored alongside the digest.
+    pub fn algorithm(&self) -> &'static str {
+        match self {
+            Hash::Sha256(_) => "sha256",
+        }
+    }
+
+    /// Raw digest bytes.
+    pub fn bytes(&self) -> &[u8] {
+        match self {
+            Hash::Sha256(bytes) => bytes,
+        }
+    }
+
+    /// Rebuild a hash from its algorithm name and raw digest, rejecting digests
+    /// of the wrong length for the algorithm.
+    pub fn from_bytes(algorithm: &str, bytes: &[u8]) -> anyhow::Result<Self> {
+        match algorithm {
+            "sha256" => {
+                let Ok(bytes) = bytes.try_into() else {
+                    anyhow::bail!("Expected 32 bytes for sha256 hash, got {}", bytes.len());
+                };
+                Ok(Hash::Sha256(bytes))
+            }
+            _ => anyhow::bail!("Unsupported hash algorithm {algorithm}"),
+        }
+    }
+
+    /// Lowercase hex rendering of the digest, two digits per byte.
+    pub fn to_hex(&self) -> String {
+        self.bytes()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    /// Parse a hex rendering of a digest, in either case.
+    pub fn from_hex(algorithm: &str, hex: &str) -> anyhow::Result<Self> {
+        if hex.len() % 2 != 0 {
+            anyhow::bail!("Hex hash has odd length {}: {hex}", hex.len());
+        }
+
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|index| {
+                let digits = hex.get(index..index + 2).unwrap_or_default();
+                u8::from_str_radix(digits, 16)
+                    .map_err(|_| anyhow::anyhow!("Invalid hex hash: {hex}"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Hash::from_bytes(algorithm, &bytes)
+    }
+}
+
+/// Renders as `algorithm:hex`, such as `sha256:e3b0...`.
+impl std::fmt::Display for Hash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.algorithm(), self.to_hex())
+    }
+}
+
+/// Parses the `algorithm:hex` form produced by `Display`.
+impl std::str::FromStr for Hash {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((algorithm, hex)) = s.split_once(':') else {
+            anyhow::bail!("Expected algorithm:hex, got {s}");
+        };
+        Hash::from_hex(algorithm, hex)
+    }
+}
 
 #[derive(Debug, Eq, PartialEq)]
 pub struct Codebase {
@@ -11,12 +165,18 @@ pub struct Codebase {
 
 /// Automatically-matched item of interest. These are generally persisted to the
 /// database for tracking movement.
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Interesting {
     // Location containing match
     pub codebase: String,
     pub revision: String,
+    /// Commit time of the revision, in seconds since the Unix epoch. Absent for
+    /// unversioned working-directory scans.
+    pub committed_at: Option<i64>,
     pub path: String,
+    /// Names of enclosing items, outermost first, such as `module/Type`. Tells
+    /// apart same-named items in different scopes.
+    pub ancestors: String,
     /// Offset from start of file, in bytes.
     pub start_byte: Option<usize>,
     /// Length of match, in bytes.
@@ -29,15 +189,107 @@ pub struct Interesting {
     pub identifier: String,
 
     // Hash details
-    pub hash_algorithm: String,
     pub salt: u64,
     /// Hash of matched data. Matched data is not stored, as it can be retrieved
     /// from the repository.
-    pub hash: String,
+    pub hash: Hash,
+    /// Hash of matched data with whitespace runs collapsed, using the same
+    /// algorithm. Equal normalized hashes mark whitespace-only changes.
+    pub normalized_hash: Hash,
 
     pub notes: Option<String>,
 }
 
+/// Progress of a reimplementation against its watched item. Known states are
+/// recognized case-insensitively, and anything else is kept verbatim.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum WatchState {
+    Todo,
+    Wip,
+    Done,
+    Broken,
+    /// Upstream changed and the reimplementation needs revisiting.
+    Update,
+    None,
+    /// Deliberately not tracked.
+    Ignore,
+    Other(String),
+}
+
+impl WatchState {
+    /// Whether this state means the item is deliberately not tracked.
+    pub fn is_ignore(&self) -> bool {
+        *self == WatchState::Ignore
+    }
+}
+
+impl std::str::FromStr for WatchState {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let state = match s.trim().to_ascii_uppercase().as_str() {
+            "TODO" => WatchState::Todo,
+            "WIP" => WatchState::Wip,
+            "DONE" => WatchState::Done,
+            "BROKEN" => WatchState::Broken,
+            "UPDATE" => WatchState::Update,
+            "NO" | "NONE" => WatchState::None,
+            "IGNORE" => WatchState::Ignore,
+            _ => WatchState::Other(s.to_string()),
+        };
+        Ok(state)
+    }
+}
+
+impl std::fmt::Display for WatchState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            WatchState::Todo => "TODO",
+            WatchState::Wip => "WIP",
+            WatchState::Done => "DONE",
+            WatchState::Broken => "BROKEN",
+            WatchState::Update => "UPDATE",
+            WatchState::None => "NONE",
+            WatchState::Ignore => "IGNORE",
+            WatchState::Other(other) => other,
+        };
+        f.write_str(name)
+    }
+}
+
+/// Pairs of matches with different kinds but identical ranges in the same file
+/// and revision. Overlap is expected between, say, a file and its functions,
+/// but an exact duplicate usually means two matchers query the same node.
+/// Items without a range are never reported.
+pub fn duplicate_ranges(items: &[Interesting]) -> Vec<(&Interesting, &Interesting)> {
+    let mut by_range = std::collections::HashMap::<_, Vec<&Interesting>>::new();
+    for item in items {
+        let (Some(start_byte), Some(length)) = (item.start_byte, item.length) else {
+            continue;
+        };
+        let key = (
+            &item.codebase,
+            &item.revision,
+            &item.path,
+            start_byte,
+            length,
+        );
+        by_range.entry(key).or_default().push(item);
+    }
+
+    let mut duplicates = Vec::new();
+    for group in by_range.values() {
+        for (index, first) in group.iter().enumerate() {
+            for second in &group[index + 1..] {
+                if first.kind != second.kind {
+                    duplicates.push((*first, *second));
+                }
+            }
+        }
+    }
+    duplicates
+}
+
 /// Corresponds to the fields of the RAWR annotation.
 /// Look up (codebase, revision, path, kind, identifier) tuple in database to
 /// find salt, then compute local checksum for comparison.
@@ -52,7 +304,320 @@ pub struct Watched {
     pub path: Option<String>,
     pub kind: Option<String>,
     pub identifier: Option<String>,
+    /// Names of the enclosing items, as in `Interesting::ancestors`. Tells
+    /// apart same-named items in different modules or types.
+    pub ancestors: Option<String>,
+    /// Offset of the upstream item from the start of its file, in bytes. Only
+    /// needed to disambiguate items sharing the same identifier, such as
+    /// overloaded functions.
+    pub start_byte: Option<usize>,
 
+    /// Reimplementation progress, if annotated.
+    pub state: Option<WatchState>,
+    /// Explicitly ignore the item. An `IGNORE` state also ignores it, so check
+    /// `is_ignored` rather than this field.
+    pub ignore: Option<bool>,
     pub notes: Option<String>,
     // TODO Optional checksum to avoid lookup?
 }
+
+impl Watched {
+    /// Whether the item is deliberately not tracked, either by `ignore = true`
+    /// or an `IGNORE` state. This is the single source of truth for ignoring.
+    pub fn is_ignored(&self) -> bool {
+        self.ignore == Some(true) || self.state.as_ref().is_some_and(WatchState::is_ignore)
+    }
+
+    /// Find the matched item that this watch refers to. Unspecified fields
+    /// match anything. When several candidates remain, prefer the one starting
+    /// closest to `start_byte`.
+    pub fn resolve<'a>(&self, candidates: &'a [Interesting]) -> Option<&'a Interesting> {
+        let found = candidates
+            .iter()
+            .filter(|item| item.codebase == self.codebase && item.revision == self.revision)
+            .filter(|item| {
+                self.path
+                    .as_ref()
+                    .is_none_or(|path| normalize_path(path) == normalize_path(&item.path))
+            })
+            .filter(|item| self.kind.as_ref().is_none_or(|kind| *kind == item.kind))
+            .filter(|item| {
+                self.identifier
+                    .as_ref()
+                    .is_none_or(|identifier| *identifier == item.identifier)
+            })
+            .filter(|item| {
+                self.ancestors
+                    .as_ref()
+                    .is_none_or(|ancestors| *ancestors == item.ancestors)
+            })
+            .collect::<Vec<_>>();
+
+        if found.len() <= 1 {
+            return found.first().copied();
+        }
+
+        let Some(start_byte) = self.start_byte else {
+            warn!(
+                "Ambiguous watch {:?} matched {} items. Using the first.",
+                self.identifier,
+                found.len()
+            );
+            return found.first().copied();
+        };
+
+        // Items without an offset sort last.
+        found.into_iter().min_by_key(|item| {
+            item.start_byte
+                .map_or(usize::MAX, |offset| offset.abs_diff(start_byte))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(identifier: &str, start_byte: usize) -> Interesting {
+        Interesting {
+            codebase: "codebase".to_string(),
+            revision: "rev".to_string(),
+            committed_at: None,
+            path: "src/lib.rs".to_string(),
+            ancestors: String::new(),
+            start_byte: Some(start_byte),
+            length: Some(10),
+            kind: "function".to_string(),
+            identifier: identifier.to_string(),
+            salt: 0,
+            hash: Hash::Sha256([0; 32]),
+            normalized_hash: Hash::Sha256([0; 32]),
+            notes: None,
+        }
+    }
+
+    fn watch(identifier: &str, start_byte: Option<usize>) -> Watched {
+        Watched {
+            codebase: "codebase".to_string(),
+            revision: "rev".to_string(),
+            path: Some("./src/lib.rs".to_string()),
+            kind: Some("function".to_string()),
+            identifier: Some(identifier.to_string()),
+            ancestors: None,
+            start_byte,
+            state: None,
+            ignore: None,
+            notes: None,
+        }
+    }
+
+    #[test]
+    fn hash_hex_round_trip() {
+        let mut bytes = [0; 32];
+        bytes[0] = 0xab;
+        bytes[31] = 0x01;
+        let hash = Hash::Sha256(bytes);
+
+        let hex = hash.to_hex();
+        assert_eq!(hex.len(), 64);
+        assert!(hex.starts_with("ab00"));
+        assert!(hex.ends_with("0001"));
+        assert_eq!(Hash::from_hex("sha256", &hex).unwrap(), hash);
+        // Either case parses to the same bytes.
+        assert_eq!(Hash::from_hex("sha256", &hex.to_uppercase()).unwrap(), hash);
+        assert_ne!(Hash::from_hex("sha256", &"00".repeat(32)).unwrap(), hash);
+
+        assert!(Hash::from_hex("sha256", "abc").is_err());
+        assert!(Hash::from_hex("sha256", &"zz".repeat(32)).is_err());
+        assert!(Hash::from_hex("sha256", &"00".repeat(31)).is_err());
+    }
+
+    #[test]
+    fn hash_display_and_parse() {
+        let hash = Hash::Sha256([0x5a; 32]);
+        let rendered = hash.to_string();
+        assert_eq!(rendered, format!("sha256:{}", "5a".repeat(32)));
+        assert_eq!(rendered.parse::<Hash>().unwrap(), hash);
+
+        assert!("5a".repeat(32).parse::<Hash>().is_err());
+        assert!(format!("md5:{}", "5a".repeat(16)).parse::<Hash>().is_err());
+    }
+
+    #[test]
+    fn hash_rejects_wrong_length() {
+        assert_eq!(
+            Hash::from_bytes("sha256", &[7; 32]).unwrap(),
+            Hash::Sha256([7; 32])
+        );
+        assert!(Hash::from_bytes("sha256", &[7; 31]).is_err());
+        assert!(Hash::from_bytes("sha256", &[7; 33]).is_err());
+        assert!(Hash::from_bytes("sha512", &[7; 64]).is_err());
+    }
+
+    #[test]
+    fn watch_state_parses_case_insensitively() {
+        for text in ["done", "Done", "DONE", " done\n"] {
+            assert_eq!(text.parse::<WatchState>(), Ok(WatchState::Done));
+        }
+        assert_eq!("wip".parse::<WatchState>(), Ok(WatchState::Wip));
+        assert_eq!("No".parse::<WatchState>(), Ok(WatchState::None));
+        assert_eq!("Ignore".parse::<WatchState>(), Ok(WatchState::Ignore));
+
+        // Unknown states are kept as written, and render the same way.
+        let other = "Blocked on upstream".parse::<WatchState>().unwrap();
+        assert_eq!(other, WatchState::Other("Blocked on upstream".to_string()));
+        assert_eq!(other.to_string(), "Blocked on upstream");
+        assert_eq!(WatchState::Wip.to_string(), "WIP");
+
+        assert!(WatchState::Ignore.is_ignore());
+        assert!(!other.is_ignore());
+    }
+
+    #[test]
+    fn ignored_by_flag_or_state() {
+        let with = |ignore: Option<bool>, state: Option<&str>| Watched {
+            ignore,
+            state: state.map(|state| state.parse().unwrap()),
+            ..watch("draw", None)
+        };
+
+        assert!(!with(None, None).is_ignored());
+        assert!(with(Some(true), None).is_ignored());
+        assert!(!with(Some(false), None).is_ignored());
+        assert!(with(None, Some("ignore")).is_ignored());
+        assert!(!with(None, Some("TODO")).is_ignored());
+
+        // Either is enough, even when the other disagrees.
+        assert!(with(Some(true), Some("IGNORE")).is_ignored());
+        assert!(with(Some(false), Some("IGNORE")).is_ignored());
+        assert!(with(Some(true), Some("DONE")).is_ignored());
+    }
+
+    #[test]
+    fn resolve_same_name_by_offset() {
+        let items = [item("new", 100), item("new", 500), item("other", 120)];
+
+        let near_first = watch("new", Some(90)).resolve(&items).unwrap();
+        assert_eq!(near_first.start_byte, Some(100));
+
+        let near_second = watch("new", Some(450)).resolve(&items).unwrap();
+        assert_eq!(near_second.start_byte, Some(500));
+
+        let unique = watch("other", None).resolve(&items).unwrap();
+        assert_eq!(unique.start_byte, Some(120));
+
+        assert!(watch("missing", Some(100)).resolve(&items).is_none());
+    }
+
+    #[test]
+    fn normalize_equivalent_paths() {
+        for path in [
+            "src/foo.rs",
+            "./src/foo.rs",
+            "src\\foo.rs",
+            "src//foo.rs",
+            ".\\src\\\\foo.rs",
+        ] {
+            assert_eq!(normalize_path(path), "src/foo.rs", "{path}");
+        }
+        assert_eq!(normalize_path("src/bin/../foo.rs"), "src/foo.rs");
+        assert_eq!(normalize_path("../foo.rs"), "../foo.rs");
+        assert_eq!(normalize_path("/home/x//a.rs"), "/home/x/a.rs");
+    }
+
+    #[test]
+    fn root_relative_paths() {
+        let root = Path::new("/repo");
+        let relative =
+            |cwd: &str, path: &str| root_relative_path(root, Path::new(cwd), Path::new(path)).ok();
+
+        assert_eq!(
+            relative("/repo", "./src//foo.rs").as_deref(),
+            Some("src/foo.rs")
+        );
+        assert_eq!(
+            relative("/repo/src", "foo.rs").as_deref(),
+            Some("src/foo.rs")
+        );
+        assert_eq!(
+            relative("/elsewhere", "/repo/src/foo.rs").as_deref(),
+            Some("src/foo.rs")
+        );
+        assert_eq!(
+            relative("/repo/src", "../lib.rs").as_deref(),
+            Some("lib.rs")
+        );
+
+        // Outside the root, including siblings sharing its prefix.
+        assert_eq!(relative("/repo", "../other/foo.rs"), None);
+        assert_eq!(relative("/repository", "foo.rs"), None);
+        assert_eq!(relative("/repo", "."), None);
+    }
+
+    #[test]
+    fn resolve_same_name_by_ancestors() {
+        let source = b"mod first {\n    fn draw() {}\n}\nmod second {\n    fn draw() {}\n}\n";
+        let items = crate::scan::find_matches_at(
+            &crate::lang::Dialect::rust().unwrap(),
+            "src/lib.rs",
+            source,
+        );
+        let ancestors = items
+            .iter()
+            .filter(|item| item.identifier == "draw")
+            .map(|item| item.ancestors.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(ancestors, ["first", "second"]);
+
+        for ancestor in ["first", "second"] {
+            let watched = Watched {
+                ancestors: Some(ancestor.to_string()),
+                ..watch("draw", None)
+            };
+            assert_eq!(watched.resolve(&items).unwrap().ancestors, ancestor);
+        }
+        let missing = Watched {
+            ancestors: Some("third".to_string()),
+            ..watch("draw", None)
+        };
+        assert_eq!(missing.resolve(&items), None);
+    }
+
+    #[test]
+    fn overlapping_matchers_are_reported() {
+        let matcher = |kind: &str| crate::lang::Matcher {
+            kind: kind.to_string(),
+            query: "((struct_item) @si)".to_string(),
+            identifier: crate::lang::MatchType::Named("name".to_string()),
+            contents: crate::lang::MatchType::Match,
+            excludes: None,
+            notes: None,
+        };
+        let dialect = crate::lang::Dialect::new(
+            crate::lang::SupportedLanguage::Rust,
+            vec![matcher("struct"), matcher("type")],
+        )
+        .unwrap();
+
+        let items = crate::scan::find_matches_at(&dialect, "src/lib.rs", b"struct A;\nstruct B;\n");
+        assert_eq!(items.len(), 4);
+
+        let mut overlaps = duplicate_ranges(&items)
+            .into_iter()
+            .map(|(first, second)| {
+                let mut kinds = [first.kind.as_str(), second.kind.as_str()];
+                kinds.sort();
+                (first.identifier.as_str(), kinds)
+            })
+            .collect::<Vec<_>>();
+        overlaps.sort();
+        assert_eq!(
+            overlaps,
+            [("A", ["struct", "type"]), ("B", ["struct", "type"])]
+        );
+
+        // Matches of the same kind, or at different ranges, aren't overlaps.
+        let same_kind = [item("a", 0), item("b", 0), item("c", 10)];
+        assert!(duplicate_ranges(&same_kind).is_empty());
+    }
+}
@@ -1,11 +1,13 @@
 // SPDX-License-Identifier: Apache-2.0
 
 #![allow(dead_code)]
+use rawr::debug::describe_matches;
 use std::collections::HashMap;
 use std::env::args;
 use std::fs::File;
 use std::io::{self, ErrorKind, Read};
-use tree_sitter::{self, Parser, Query, QueryCursor, Tree};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tree_sitter::{self, Parser, Query, QueryError, Tree};
 use tree_sitter_bash;
 use tree_sitter_rust;
 use tree_sitter_traversal as tst;
@@ -36,25 +38,65 @@ const ANNOTATION_QUERY: &str = "
 /// Match key-value pairs in attribute arguments
 /// TODO Test replacement of iterator
 const ANNOTATION_ATTRIBUTE_QUERY: &str = "
-(arguments: (token_tree ((identifier) @key . \"=\" . (_literal) @val)* @pair))
+(attribute
+  arguments: (token_tree (identifier) @key . \"=\" . (_literal) @val))
 ";
 
+/// Annotation queries, compiled once and shared by every file.
+struct AnnotationQueries {
+    annotation: Query,
+    attribute: Query,
+}
+
+/// Times the annotation queries have been compiled.
+static COMPILATIONS: AtomicUsize = AtomicUsize::new(0);
+
+impl AnnotationQueries {
+    fn new() -> Result<Self, QueryError> {
+        COMPILATIONS.fetch_add(1, Ordering::Relaxed);
+        let language = tree_sitter_rust::language();
+        Ok(AnnotationQueries {
+            annotation: Query::new(language, ANNOTATION_QUERY)?,
+            attribute: Query::new(language, ANNOTATION_ATTRIBUTE_QUERY)?,
+        })
+    }
+}
+
 fn main() -> Result<(), io::Error> {
-    let args: Vec<String> = args().collect();
-    if args.len() < 3 {
-        return Err(io::Error::new(
-            ErrorKind::InvalidInput,
-            "Usage: rawr rust_file bash_file",
-        ));
+    let args: Vec<String> = args().skip(1).collect();
+    let Some((upstream_file, implementation_files)) = args.split_last() else {
+        return Err(usage());
+    };
+    if implementation_files.is_empty() {
+        return Err(usage());
     }
-    let implementation_file = args.get(1).unwrap();
-    let upstream_file = args.get(2).unwrap();
 
-    parse_annotations(implementation_file);
+    parse_all_annotations(implementation_files);
     parse_bash(upstream_file);
     Ok(())
 }
 
+fn usage() -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidInput,
+        "Usage: rawr rust_file... bash_file",
+    )
+}
+
+/// Print the annotations in each Rust file. Queries are compiled once, and the
+/// parser is reused, however many files there are.
+fn parse_all_annotations(source_files: &[String]) {
+    let queries = AnnotationQueries::new().expect("Compile annotation queries");
+    let mut parser = Parser::new();
+    parser
+        .set_language(tree_sitter_rust::language())
+        .expect("Create Rust parser");
+
+    for source_file in source_files {
+        parse_annotations(source_file, &mut parser, &queries);
+    }
+}
+
 fn parse_bash(source_file: &String) {
     println!("--- Bash ---");
     let mut parser = Parser::new();
@@ -79,104 +121,37 @@ fn parse_bash(source_file: &String) {
 
     // Find variable FOO
     let query = "(variable_assignment (variable_name) @var \"=\" (_) @body (#eq? @var \"FOO\"))";
-    print_matches(query, &source_bytes, &tree);
+    let query = Query::new(tree.language(), query).expect("Create query");
+    print_matches(&query, &source_bytes, &tree);
 
     let query = "
     (([(function_definition) (variable_assignment)]) @def)";
-
-    print_matches(query, &source_bytes, &tree);
+    let query = Query::new(tree.language(), query).expect("Create query");
+    print_matches(&query, &source_bytes, &tree);
 }
 
-fn print_matches(query_string: &str, source_bytes: &Vec<u8>, tree: &Tree) {
-    let query = Query::new(tree.language(), query_string).expect("Create query");
-    let mut query_cursor = QueryCursor::new();
-    let matches = query_cursor.matches(&query, tree.root_node(), source_bytes.as_slice());
-    matches.for_each(|m| {
-        println!("Match {}: {:?}", m.pattern_index, m);
+fn print_matches(query: &Query, source_bytes: &[u8], tree: &Tree) {
+    for described in describe_matches(query, source_bytes, tree) {
+        println!("Match {}:", described.pattern_index);
 
-        m.captures.iter().for_each(|cap| {
-            let node = cap.node;
-            println!(
-                "\t{}: {:?}, {} named children",
-                cap.index,
-                cap,
-                node.named_child_count()
-            );
+        for cap in described.captures {
             println!(
-                "\t\t{:?} {:?}",
-                String::from_utf8_lossy(&source_bytes[node.start_byte()..node.end_byte()]),
-                node.to_sexp(),
+                "\t{}: @{} {} {:?}, {} named children",
+                cap.index, cap.name, cap.kind, cap.range, cap.named_children
             );
-
-            // Grammars with named children are easier to pick apart.
-            match node.kind() {
-                "function_definition" => {
-                    if let Some(name) = node.child_by_field_name("name") {
-                        if let Some(body) = node.child_by_field_name("body") {
-                            println!(
-                                "\t\t{} -> {:?}",
-                                String::from_utf8_lossy(
-                                    &source_bytes[name.start_byte()..name.end_byte()]
-                                ),
-                                String::from_utf8_lossy(
-                                    &source_bytes[body.start_byte()..body.end_byte()]
-                                )
-                            )
-                        }
-                    }
-                }
-                "variable_assignment" => {
-                    if let Some(name) = node.child_by_field_name("name") {
-                        if let Some(value) = node.child_by_field_name("value") {
-                            println!(
-                                "\t\t{} = {:?} -- {}",
-                                String::from_utf8_lossy(
-                                    &source_bytes[name.start_byte()..name.end_byte()]
-                                ),
-                                String::from_utf8_lossy(
-                                    &source_bytes[value.start_byte()..value.end_byte()]
-                                ),
-                                node.to_sexp()
-                            );
-                        }
-                    }
-                }
-                "attribute" => {
-                    if let Some(args) = node.child_by_field_name("arguments") {
-                        // Named children should form key-value pairs.
-                        let mut tree_cursor = args.walk();
-                        let mut children = args.named_children(&mut tree_cursor).into_iter();
-
-                        while let Some(key) = children.next() {
-                            if let Some(val) = children.next() {
-                                println!(
-                                    "\t\t\tArgument: {} = ({}) {}",
-                                    String::from_utf8_lossy(
-                                        &source_bytes[key.start_byte()..key.end_byte()]
-                                    ),
-                                    val.kind(),
-                                    String::from_utf8_lossy(
-                                        &source_bytes[val.start_byte()..val.end_byte()]
-                                    )
-                                )
-                            }
-                        }
-                    }
-                }
-                _ => {}
-            };
-        });
-    });
+            println!("\t\t{:?} {:?}", cap.text, cap.sexp);
+            for detail in cap.details {
+                println!("\t\t\t{detail}");
+            }
+        }
+    }
 }
 
-fn parse_annotations(source_file: &String) {
+fn parse_annotations(source_file: &String, parser: &mut Parser, queries: &AnnotationQueries) {
     // TODO Iterate over all paths in all codebases.
 
     // see: https://github.com/tree-sitter/tree-sitter/tree/master/lib/binding_rust
-    let mut parser = Parser::new();
-    parser
-        .set_language(tree_sitter_rust::language())
-        .expect("Create Rust parser");
+    println!("--- {source_file} ---");
     let mut source_file = File::open(source_file).expect("Open test file");
     let mut source_bytes = Vec::new();
     source_file
@@ -196,7 +171,10 @@ fn parse_annotations(source_file: &String) {
     // see https://deepsource.com/blog/lightweight-linting
     println!("--- Matches ---");
 
-    print_matches(ANNOTATION_QUERY, &source_bytes, &tree);
+    print_matches(&queries.annotation, &source_bytes, &tree);
+
+    println!("--- Arguments ---");
+    print_matches(&queries.attribute, &source_bytes, &tree);
 }
 
 /// Common options for annotations
@@ -208,6 +186,8 @@ pub struct Rawr {
     rev: String,
     /// Path to original file, relative to codebase root
     path: Option<String>,
+    /// Byte offset of the original item, disambiguating duplicate identifiers.
+    offset: Option<usize>,
     /// Tree-Sitter query identifying the upstream implementation.
     /// Mutually exclusive to class/function/symbol.
     query: Option<String>,
@@ -220,8 +200,9 @@ pub struct Rawr {
     symbol: Option<String>,
     /// Free-form notes regarding the implementation.
     notes: Option<String>,
-    /// Free-form implementation status. Special case for NO, NONE, WIP, DONE, BROKEN, UPDATE.
-    implemented: Option<String>,
+    /// Free-form implementation status. Known states such as WIP or DONE are
+    /// recognized case-insensitively.
+    implemented: Option<rawr::WatchState>,
     /// Hash of implementation body, without whitespace or comments.
     hash: Option<String>,
     /// Hash of implementation body, comments stripped, and normalized whitespace.
@@ -234,3 +215,34 @@ pub struct Codebase {
     /// Mapping of paths to parser configurations.
     paths: HashMap<String, tree_sitter::Language>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queries_compile_once_for_many_files() {
+        let dir = std::env::temp_dir().join(format!("rawr-main-{:016x}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let sources = [
+            "#[rawr(codebase = \"upstream\", rev = \"abc123\")]\nfn first() {}\n",
+            "#[rawr(rev = \"def456\", notes = \"a, (b)\")]\nstruct Second;\n",
+        ];
+        let files = sources
+            .iter()
+            .enumerate()
+            .map(|(index, source)| {
+                let path = dir.join(format!("{index}.rs"));
+                std::fs::write(&path, source).unwrap();
+                path.to_string_lossy().into_owned()
+            })
+            .collect::<Vec<_>>();
+
+        let before = COMPILATIONS.load(Ordering::Relaxed);
+        parse_all_annotations(&files);
+        let compiled = COMPILATIONS.load(Ordering::Relaxed) - before;
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(compiled, 1);
+    }
+}
@@ -0,0 +1,1879 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Find items of interest in a file, using a dialect's matchers, and hash
+//! their contents.
+
+use crate::lang::{
+    ancestor_path, looks_binary, normalize_whitespace, outer_capture, satisfies_predicates,
+    CompiledMatcher, Dialect, ExtractionError, MatchType, SupportedLanguage,
+};
+use crate::{matched_outer_range, normalize_path, Hash, Interesting};
+use anyhow::{bail, Context};
+use encoding_rs::Encoding;
+use gix::object::Kind;
+use gix::ObjectId;
+use rusqlite::Connection;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fmt::{self, Display, Formatter};
+use std::io::ErrorKind;
+use std::ops::Range;
+use std::path::Path;
+use tracing::{debug, info_span, warn};
+use tree_sitter::{Parser, QueryCursor, QueryMatch};
+
+/// Files larger than this are skipped unless configured otherwise.
+pub const DEFAULT_MAX_FILE_SIZE: usize = 1024 * 1024;
+
+/// Totals for a scan, reported when it finishes.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct ScanStats {
+    pub revisions: usize,
+    /// Files parsed and searched for matches.
+    pub parsed: usize,
+    /// Files whose blob was already parsed at an earlier revision.
+    pub cache_hits: usize,
+    /// Binary, oversized, or unrecognized files.
+    pub unsupported: usize,
+    /// Files that failed to parse.
+    pub unparseable: usize,
+    /// Files that don't exist at a revision, such as before they were added.
+    pub missing: usize,
+    /// Files that exist but couldn't be read.
+    pub unreadable: usize,
+    pub matches: usize,
+    /// Matches in parsed files that were missing an optional part, or had an
+    /// identifier that isn't valid UTF-8.
+    pub skipped: usize,
+}
+
+impl Display for ScanStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let lookups = self.parsed + self.cache_hits;
+        let hit_rate = if lookups > 0 {
+            100.0 * self.cache_hits as f64 / lookups as f64
+        } else {
+            0.0
+        };
+
+        write!(
+            f,
+            "Scanned {} revisions: {} files parsed, {} cache hits ({:.1}%), {} unsupported, {} unparseable, {} missing, {} unreadable, {} matches, {} skipped.",
+            self.revisions,
+            self.parsed,
+            self.cache_hits,
+            hit_rate,
+            self.unsupported,
+            self.unparseable,
+            self.missing,
+            self.unreadable,
+            self.matches,
+            self.skipped
+        )
+    }
+}
+
+/// Scans files across revisions of a repository, or in the working directory.
+/// Each distinct blob is only parsed once per path, however many revisions
+/// contain it.
+pub struct Scanner {
+    pub codebase: String,
+    pub dialects: HashMap<SupportedLanguage, Dialect>,
+    /// Files larger than this are skipped. Images, archives, and minified
+    /// vendor files only produce noise.
+    pub max_size: usize,
+    pub stats: ScanStats,
+    parser: Parser,
+    /// Matches for each (dialect version, path, blob), so that files
+    /// unchanged between revisions are only parsed once. Changing a dialect's
+    /// matchers changes its version, so stale matches are never reused.
+    memo: HashMap<(Hash, String, ObjectId), Vec<Interesting>>,
+    /// Database of matches for blobs scanned by earlier runs. Blobs found there
+    /// aren't read at all, and new blobs are added once scanned.
+    pub cache: Option<Connection>,
+}
+
+impl Scanner {
+    pub fn new(codebase: &str, dialects: HashMap<SupportedLanguage, Dialect>) -> Self {
+        Scanner {
+            codebase: codebase.to_string(),
+            dialects,
+            max_size: DEFAULT_MAX_FILE_SIZE,
+            stats: ScanStats::default(),
+            parser: Parser::new(),
+            memo: HashMap::new(),
+            cache: None,
+        }
+    }
+
+    /// Content hash of everything that decides a file's matches: each
+    /// dialect's version and the size limit. Cached matches are keyed by it.
+    pub fn version(&self) -> Hash {
+        let mut versions = self
+            .dialects
+            .values()
+            .map(Dialect::version)
+            .collect::<Vec<_>>();
+        versions.sort_by(|a, b| a.bytes().cmp(b.bytes()));
+
+        let mut digest = Sha256::new();
+        for version in &versions {
+            digest.update(version.bytes());
+        }
+        digest.update(self.max_size.to_le_bytes());
+        Hash::Sha256(digest.finalize().into())
+    }
+
+    /// Scan files as they were at a commit. Files missing from the commit's
+    /// tree are skipped, as are files that can't be read.
+    pub fn scan_revision(
+        &mut self,
+        repo: &gix::Repository,
+        id: ObjectId,
+        files: &[String],
+    ) -> anyhow::Result<Vec<Interesting>> {
+        let commit = repo
+            .find_object(id)?
+            .peel_to_kind(Kind::Commit)?
+            .into_commit();
+        let committed_at = commit.time()?.seconds as i64;
+        let tree = commit.tree()?;
+        let revision = id.to_string();
+        let _span =
+            info_span!("revision", codebase = %self.codebase, revision = %revision).entered();
+
+        self.stats.revisions += 1;
+        let version = self.cache.as_ref().map(|_| self.version());
+        let mut found = Vec::new();
+        for file in files {
+            let path = Path::new(file);
+            let oid = match blob_id(&tree, path) {
+                Ok(Some(oid)) => oid,
+                Ok(None) => {
+                    debug!("{} is missing", path.display());
+                    self.stats.missing += 1;
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Skipping unreadable {}: {e:#}", path.display());
+                    self.stats.unreadable += 1;
+                    continue;
+                }
+            };
+
+            if let Some(cached) = version
+                .as_ref()
+                .and_then(|version| self.cached(version, path, oid))
+            {
+                self.stats.cache_hits += 1;
+                self.stats.matches += cached.len();
+                found.extend(cached.into_iter().map(|item| Interesting {
+                    codebase: self.codebase.clone(),
+                    revision: revision.clone(),
+                    committed_at: Some(committed_at),
+                    ..item
+                }));
+                continue;
+            }
+
+            let source_bytes = match read_blob(repo, oid) {
+                Ok(source_bytes) => source_bytes,
+                Err(e) => {
+                    warn!("Skipping unreadable {}: {e:#}", path.display());
+                    self.stats.unreadable += 1;
+                    continue;
+                }
+            };
+            let matches = self.scan_file(
+                path,
+                &revision,
+                Some(committed_at),
+                Some(oid),
+                &source_bytes,
+            );
+            if let Some(version) = &version {
+                self.store_cached(version, path, oid, &matches);
+            }
+            found.extend(matches);
+        }
+        sort_matches(&mut found);
+        Ok(found)
+    }
+
+    /// Matches cached for a blob by an earlier run. Failing to read the cache
+    /// only costs a rescan.
+    fn cached(&self, version: &Hash, path: &Path, oid: ObjectId) -> Option<Vec<Interesting>> {
+        let conn = self.cache.as_ref()?;
+        let path = normalize_path(&path.to_string_lossy());
+        match crate::db::cached_matches(conn, version, &path, &oid.to_string()) {
+            Ok(cached) => cached,
+            Err(e) => {
+                warn!("Failed to read cached matches: {e:#}");
+                None
+            }
+        }
+    }
+
+    fn store_cached(
+        &mut self,
+        version: &Hash,
+        path: &Path,
+        oid: ObjectId,
+        matches: &[Interesting],
+    ) {
+        let Some(conn) = self.cache.as_mut() else {
+            return;
+        };
+        let path = normalize_path(&path.to_string_lossy());
+        if let Err(e) = crate::db::cache_matches(conn, version, &path, &oid.to_string(), matches) {
+            warn!("Failed to cache matches: {e:#}");
+        }
+    }
+
+    /// Scan files in the working directory under `root`, as an unversioned
+    /// revision. Files are given relative to the root.
+    pub fn scan_worktree(&mut self, root: &Path, files: &[String]) -> Vec<Interesting> {
+        let _span =
+            info_span!("revision", codebase = %self.codebase, revision = "(unversioned)").entered();
+        self.stats.revisions += 1;
+        let mut found = Vec::new();
+        for file in files {
+            let path = Path::new(file);
+            let source_bytes = match std::fs::read(root.join(path)) {
+                Ok(source_bytes) => source_bytes,
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    debug!("{} is missing", path.display());
+                    self.stats.missing += 1;
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Skipping unreadable {}: {e}", path.display());
+                    self.stats.unreadable += 1;
+                    continue;
+                }
+            };
+            found.extend(self.scan_file(path, "(unversioned)", None, None, &source_bytes));
+        }
+        sort_matches(&mut found);
+        found
+    }
+
+    /// Find matches in a single file, skipping anything that can't or
+    /// shouldn't be parsed. A single unparseable file doesn't abort the scan.
+    fn scan_file(
+        &mut self,
+        path: &Path,
+        revision: &str,
+        committed_at: Option<i64>,
+        oid: Option<ObjectId>,
+        source_bytes: &[u8],
+    ) -> Vec<Interesting> {
+        let _span = info_span!("file", path = %path.display()).entered();
+
+        // Binary and oversized files are skipped before language detection.
+        if source_bytes.len() > self.max_size || looks_binary(source_bytes) {
+            debug!("Skipping binary or oversized file");
+            self.stats.unsupported += 1;
+            return Vec::new();
+        }
+
+        let dialect = SupportedLanguage::detect(path, source_bytes)
+            .and_then(|language| self.dialects.get(&language));
+        let Some(dialect) = dialect else {
+            debug!("Skipping file in unsupported language");
+            self.stats.unsupported += 1;
+            return Vec::new();
+        };
+
+        let key = oid.map(|oid| {
+            (
+                dialect.version(),
+                normalize_path(&path.to_string_lossy()),
+                oid,
+            )
+        });
+        if let Some(cached) = key.as_ref().and_then(|key| self.memo.get(key)) {
+            debug!("Reusing matches for unchanged blob");
+            self.stats.cache_hits += 1;
+            self.stats.matches += cached.len();
+            return cached
+                .iter()
+                .map(|item| Interesting {
+                    revision: revision.to_string(),
+                    committed_at,
+                    ..item.clone()
+                })
+                .collect();
+        }
+
+        let context = ScanContext {
+            codebase: &self.codebase,
+            revision,
+            path,
+        };
+        let found = find_matches_in_file(&mut self.parser, &context, source_bytes, dialect);
+        let FileMatches {
+            items: mut matches,
+            skipped,
+        } = match found {
+            Ok(found) => found,
+            Err(e) => {
+                warn!("Skipping unparseable file: {e:#}");
+                self.stats.unparseable += 1;
+                return Vec::new();
+            }
+        };
+
+        for (kind, reason) in &skipped {
+            match reason {
+                // Usually a matcher capturing the wrong node, so worth seeing.
+                Skipped::InvalidIdentifier(_) => {
+                    warn!("Skipping {kind} in {}: {reason}", path.display())
+                }
+                _ => debug!("Skipping {kind}: {reason}"),
+            }
+        }
+        debug!("Found {} matches", matches.len());
+        self.stats.parsed += 1;
+        self.stats.matches += matches.len();
+        self.stats.skipped += skipped.len();
+        for item in &mut matches {
+            item.committed_at = committed_at;
+        }
+        if let Some(key) = key {
+            self.memo.insert(key, matches.clone());
+        }
+        matches
+    }
+}
+
+/// Commits reachable from `to` but not from `from`, oldest first. Like git's
+/// `from..to`, both histories are walked newest first, and the walk stops once
+/// only `from`'s ancestors remain. Only the range and its boundary are read,
+/// however long the history before `from`.
+// DESIGN As in git, commit times are assumed to mostly increase from parent to
+// child. Heavily skewed clocks can let an ancestor of `from` into the range.
+pub fn revision_range(
+    repo: &gix::Repository,
+    from: &str,
+    to: &str,
+) -> anyhow::Result<Vec<ObjectId>> {
+    let from = repo.rev_parse_single(from)?.detach();
+    let to = repo.rev_parse_single(to)?.detach();
+
+    let mut walk = RangeWalk {
+        repo,
+        queue: BinaryHeap::new(),
+        state: HashMap::new(),
+        visible: 0,
+    };
+    walk.push(from, true)?;
+    walk.push(to, false)?;
+
+    let mut included = Vec::<ObjectId>::new();
+    while walk.visible > 0 {
+        let Some((_, id)) = walk.queue.pop() else {
+            break;
+        };
+        let hidden = walk.state.get_mut(&id).map_or(true, |state| {
+            state.queued = false;
+            state.hidden
+        });
+        if !hidden {
+            walk.visible -= 1;
+            included.push(id);
+        }
+
+        let commit = repo
+            .find_object(id)?
+            .peel_to_kind(Kind::Commit)?
+            .into_commit();
+        for parent in commit.parent_ids() {
+            walk.push(parent.detach(), hidden)?;
+        }
+    }
+
+    included.reverse();
+    Ok(included)
+}
+
+/// Queue of commits for `revision_range`, newest first.
+struct RangeWalk<'repo> {
+    repo: &'repo gix::Repository,
+    queue: BinaryHeap<(i64, ObjectId)>,
+    state: HashMap<ObjectId, WalkState>,
+    /// Queued commits that aren't hidden. The walk ends when none remain.
+    visible: usize,
+}
+
+struct WalkState {
+    /// Reachable from the start of the range.
+    hidden: bool,
+    queued: bool,
+}
+
+impl RangeWalk<'_> {
+    /// Queue a commit, or hide an already-queued one.
+    fn push(&mut self, id: ObjectId, hidden: bool) -> anyhow::Result<()> {
+        if let Some(state) = self.state.get_mut(&id) {
+            if hidden && !state.hidden {
+                state.hidden = true;
+                if state.queued {
+                    self.visible -= 1;
+                }
+            }
+            return Ok(());
+        }
+
+        let commit = self
+            .repo
+            .find_object(id)?
+            .peel_to_kind(Kind::Commit)?
+            .into_commit();
+        let time = commit.time()?.seconds as i64;
+        self.queue.push((time, id));
+        self.state.insert(
+            id,
+            WalkState {
+                hidden,
+                queued: true,
+            },
+        );
+        if !hidden {
+            self.visible += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Newest scanned revision in the history of `target`, from which a scan can
+/// resume. Revisions on other branches are never chosen, however recently
+/// they were committed.
+pub fn resume_point(
+    repo: &gix::Repository,
+    target: ObjectId,
+    scanned: &HashSet<String>,
+) -> anyhow::Result<Option<ObjectId>> {
+    for info in repo.rev_walk([target]).all()? {
+        let id = info?.id;
+        if scanned.contains(&id.to_string()) {
+            return Ok(Some(id));
+        }
+    }
+    Ok(None)
+}
+
+/// Read a file from a tree, along with its blob's object id. Paths are
+/// relative to the repository root. Paths that aren't in the tree are `None`.
+pub fn read_source(tree: &gix::Tree, path: &Path) -> anyhow::Result<Option<(ObjectId, Vec<u8>)>> {
+    let Some(oid) = blob_id(tree, path)? else {
+        return Ok(None);
+    };
+    Ok(Some((oid, read_blob(tree.repo, oid)?)))
+}
+
+/// Id of a file's blob in a tree, without reading its contents.
+pub fn blob_id(tree: &gix::Tree, path: &Path) -> anyhow::Result<Option<ObjectId>> {
+    // Trees only contain canonical relative paths.
+    let tree_path = normalize_path(&path.to_string_lossy());
+
+    let mut buf = Vec::<u8>::new();
+    let Some(entry) = tree.lookup_entry_by_path(Path::new(&tree_path), &mut buf)? else {
+        return Ok(None);
+    };
+    if !entry.mode().is_blob() {
+        bail!("{} is not a file", path.display());
+    }
+
+    Ok(Some(entry.object_id()))
+}
+
+fn read_blob(repo: &gix::Repository, oid: ObjectId) -> anyhow::Result<Vec<u8>> {
+    Ok(repo.find_object(oid)?.try_into_blob()?.take_data())
+}
+
+/// Items found in a file with a fresh parser, as codebase `codebase` at
+/// revision `rev`. Shared by tests across modules.
+#[cfg(test)]
+pub(crate) fn find_matches_at(dialect: &Dialect, path: &str, source: &[u8]) -> Vec<Interesting> {
+    let context = ScanContext {
+        codebase: "codebase",
+        revision: "rev",
+        path: Path::new(path),
+    };
+    find_matches_in_file(&mut Parser::new(), &context, source, dialect)
+        .unwrap()
+        .items
+}
+
+/// Where a scanned file came from, as recorded in each of its matches.
+#[derive(Clone, Copy, Debug)]
+pub struct ScanContext<'a> {
+    pub codebase: &'a str,
+    pub revision: &'a str,
+    /// Path of the file, relative to the codebase root.
+    pub path: &'a Path,
+}
+
+/// A query match that didn't produce an item.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Skipped {
+    /// An optional identifier part was absent.
+    Identifier(ExtractionError),
+    /// An optional contents part was absent.
+    Contents(ExtractionError),
+    /// Lossy conversion would replace invalid bytes, so that distinct
+    /// identifiers could collide and never match consistently across
+    /// revisions. Holds an excerpt of the identifier.
+    InvalidIdentifier(String),
+    /// The match has no capture to locate the item by.
+    NoOuterCapture,
+}
+
+impl Display for Skipped {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Skipped::Identifier(e) => write!(f, "Failed to match identifier: {e}"),
+            Skipped::Contents(e) => write!(f, "Failed to match contents: {e}"),
+            Skipped::InvalidIdentifier(excerpt) => {
+                write!(f, "Identifier is not valid UTF-8: {excerpt}")
+            }
+            Skipped::NoOuterCapture => write!(f, "Match has no outer capture"),
+        }
+    }
+}
+
+/// Items found in a file, along with the matches that were skipped.
+#[derive(Debug, Default)]
+pub struct FileMatches {
+    pub items: Vec<Interesting>,
+    pub skipped: Vec<(String, Skipped)>,
+}
+
+/// Parse a file and extract every item that the dialect's matchers find.
+/// Parsers are reused between files. Switching languages is cheap, while the
+/// dialect's queries are already compiled.
+pub fn find_matches_in_file(
+    parser: &mut Parser,
+    context: &ScanContext,
+    source_bytes: &[u8],
+    dialect: &Dialect,
+) -> anyhow::Result<FileMatches> {
+    // Tree-Sitter only reads UTF-8.
+    let transcoded = dialect
+        .encoding
+        .map(|encoding| transcode(encoding, source_bytes));
+    let source_bytes = transcoded
+        .as_ref()
+        .map_or(source_bytes, |transcoded| transcoded.text.as_slice());
+
+    // Parse file
+    parser
+        .set_language(dialect.grammar)
+        .with_context(|| format!("Create {:?} parser", dialect.language))?;
+
+    let tree = parser
+        .parse(source_bytes, None)
+        .context("Parser did not produce a tree")?;
+
+    // Find matches
+    let mut found = FileMatches::default();
+    let mut exclude_cursor = QueryCursor::new();
+    for compiled in &dialect.matchers {
+        let query = &compiled.query;
+
+        // Find matches and extract information
+        let mut cursor = QueryCursor::new();
+        let matches = cursor.matches(query, tree.root_node(), source_bytes);
+        let included = matches
+            .filter(|matched| satisfies_predicates(query, matched, source_bytes))
+            .filter(|matched| !compiled.is_excluded(matched, source_bytes, &mut exclude_cursor));
+        for matched in included {
+            match process_match(context, source_bytes, compiled, &matched)? {
+                Ok(item) => found.items.push(item),
+                Err(skipped) => found.skipped.push((compiled.matcher.kind.clone(), skipped)),
+            }
+        }
+    }
+
+    // Offsets refer to the original bytes, rather than the transcoded text.
+    if let Some(transcoded) = &transcoded {
+        for item in &mut found.items {
+            let (Some(start), Some(length)) = (item.start_byte, item.length) else {
+                continue;
+            };
+            let original = transcoded.original_range(start..start + length);
+            item.start_byte = Some(original.start);
+            item.length = Some(original.len());
+        }
+    }
+
+    // Matchers run one after another, so sort for stable output and diffs.
+    sort_matches(&mut found.items);
+    Ok(found)
+}
+
+/// Order matches by path, then position, kind, and identifier, so that output
+/// and database batches don't depend on the order of matchers or files.
+pub fn sort_matches(items: &mut [Interesting]) {
+    items.sort_by(|a, b| {
+        a.path
+            .cmp(&b.path)
+            .then(a.start_byte.cmp(&b.start_byte))
+            .then_with(|| a.kind.cmp(&b.kind))
+            .then_with(|| a.identifier.cmp(&b.identifier))
+    });
+}
+
+/// Build an item from a single match. Matches missing an optional part, or
+/// with an identifier that isn't valid UTF-8, are skipped rather than failing
+/// the file.
+fn process_match(
+    context: &ScanContext,
+    source_bytes: &[u8],
+    compiled: &CompiledMatcher,
+    matched: &QueryMatch,
+) -> Result<Result<Interesting, Skipped>, ExtractionError> {
+    let matcher = &compiled.matcher;
+    let Some(root_match) = outer_capture(&compiled.query, matched) else {
+        return Ok(Err(Skipped::NoOuterCapture));
+    };
+
+    let file_path = context.path.to_string_lossy();
+
+    // Identifier: Extract a string
+    let identifier_bytes = extract(
+        &matcher.identifier,
+        compiled,
+        matched,
+        source_bytes,
+        file_path.as_ref(),
+    );
+
+    // Items missing an optional part are skipped. Anything else is a bug.
+    let identifier = match identifier_bytes {
+        Ok(identifier) => identifier,
+        Err(e) if e.is_skippable() => return Ok(Err(Skipped::Identifier(e))),
+        Err(e) => return Err(e),
+    };
+
+    let Ok(identifier) = std::str::from_utf8(&identifier) else {
+        return Ok(Err(Skipped::InvalidIdentifier(excerpt(&identifier))));
+    };
+
+    // DESIGN Hashes are unsalted so that they can be compared between scans.
+    // The salt is stored for a salted hash of sensitive contents, which isn't
+    // computed yet.
+    let salt: u64 = rand::random();
+    let mut digest = Sha256::new();
+    let mut normalized_digest = Sha256::new();
+    let mut collapser = WhitespaceCollapser::default();
+
+    // Contents are hashed as they are extracted, rather than copied out first.
+    let hashed = checksum(
+        &matcher.contents,
+        compiled,
+        matched,
+        source_bytes,
+        file_path.as_ref(),
+        &mut |bytes| {
+            digest.update(bytes);
+            collapser.feed(bytes, &mut |run| normalized_digest.update(run));
+        },
+    );
+
+    match hashed {
+        Ok(()) => {}
+        Err(e) if e.is_skippable() => return Ok(Err(Skipped::Contents(e))),
+        Err(e) => return Err(e),
+    };
+
+    let hash = Hash::Sha256(digest.finalize().into());
+    let normalized_hash = Hash::Sha256(normalized_digest.finalize().into());
+
+    Ok(Ok(Interesting {
+        codebase: context.codebase.to_string(),
+        revision: context.revision.to_string(),
+        committed_at: None,
+        path: normalize_path(&file_path),
+        ancestors: ancestor_path(root_match.node, source_bytes),
+        start_byte: Some(root_match.node.start_byte()),
+        length: Some(root_match.node.end_byte() - root_match.node.start_byte()),
+        kind: matcher.kind.to_string(),
+        identifier: identifier.to_string(),
+        salt,
+        hash,
+        normalized_hash,
+        notes: None,
+    }))
+}
+
+/// Collapses whitespace in a stream of chunks, as `normalize_whitespace` does
+/// for a whole buffer. Runs split across chunks are collapsed too.
+#[derive(Default)]
+struct WhitespaceCollapser {
+    /// Whether any non-whitespace has been emitted, so leading space is dropped.
+    started: bool,
+    /// Whitespace seen since the last emitted run. Emitted as a single space
+    /// before the next run, so trailing space is dropped.
+    pending: bool,
+}
+
+impl WhitespaceCollapser {
+    fn feed(&mut self, bytes: &[u8], update: &mut dyn FnMut(&[u8])) {
+        for run in bytes.chunk_by(|a, b| a.is_ascii_whitespace() == b.is_ascii_whitespace()) {
+            if run[0].is_ascii_whitespace() {
+                self.pending = self.started;
+                continue;
+            }
+            if self.pending {
+                update(b" ");
+                self.pending = false;
+            }
+            update(run);
+            self.started = true;
+        }
+    }
+}
+
+/// Source transcoded to UTF-8, with the offset of each transcoded byte in the
+/// original source.
+struct Transcoded {
+    text: Vec<u8>,
+    /// One more than `text`, so that the end of the text maps to the end of
+    /// the source.
+    offsets: Vec<usize>,
+}
+
+impl Transcoded {
+    /// Range in the original source of a range of transcoded text. Ranges from
+    /// the tree always lie on character boundaries.
+    fn original_range(&self, range: Range<usize>) -> Range<usize> {
+        self.offsets[range.start]..self.offsets[range.end]
+    }
+}
+
+/// Transcode source to UTF-8 a byte at a time, so that each character's
+/// original offset is known. Malformed sequences become replacement
+/// characters.
+fn transcode(encoding: &'static Encoding, source_bytes: &[u8]) -> Transcoded {
+    let mut decoder = encoding.new_decoder_without_bom_handling();
+    let mut text = Vec::with_capacity(source_bytes.len());
+    let mut offsets = Vec::with_capacity(source_bytes.len() + 1);
+    let mut buf = vec![0; decoder.max_utf8_buffer_length(1).unwrap_or(16).max(16)];
+
+    // Characters start at the first byte not yet decoded, and are produced by
+    // their last byte.
+    let mut start = 0;
+    for (index, byte) in source_bytes.iter().enumerate() {
+        let (_, _, written, _) =
+            decoder.decode_to_utf8(std::slice::from_ref(byte), &mut buf, false);
+        if written > 0 {
+            text.extend_from_slice(&buf[..written]);
+            offsets.resize(text.len(), start);
+            start = index + 1;
+        }
+    }
+    let (_, _, written, _) = decoder.decode_to_utf8(&[], &mut buf, true);
+    text.extend_from_slice(&buf[..written]);
+    offsets.resize(text.len(), start);
+    offsets.push(source_bytes.len());
+
+    Transcoded { text, offsets }
+}
+
+/// Extract bytes from a match. Borrows from the source where possible.
+pub fn extract<'a>(
+    match_type: &MatchType,
+    compiled: &CompiledMatcher,
+    matched: &QueryMatch,
+    source_bytes: &'a [u8],
+    file_path: &str,
+) -> Result<Cow<'a, [u8]>, ExtractionError> {
+    let query = &compiled.query;
+    let root_match = outer_capture(query, matched).ok_or(ExtractionError::NoCaptures)?;
+
+    // Ranges come from the tree, but guard against a tree from different source.
+    let slice = |range: Range<usize>| match source_bytes.get(range.clone()) {
+        Some(bytes) => Ok(Cow::from(bytes)),
+        None => Err(ExtractionError::MatchBytesOutOfBounds(range)),
+    };
+
+    match match_type {
+        MatchType::Match => slice(root_match.node.start_byte()..root_match.node.end_byte()),
+        MatchType::Span => slice(matched_outer_range(matched).ok_or(ExtractionError::NoCaptures)?),
+        // TODO Iterate over all children for anything matching type, and pick index.
+        MatchType::Kind(_index, _kind) => Err(ExtractionError::Unsupported("Kind".to_string())),
+        MatchType::Named(child_name) => {
+            let node = root_match
+                .node
+                .child_by_field_name(child_name)
+                .ok_or_else(|| ExtractionError::NamedMatchNotFound(child_name.to_string()))?;
+            slice(node.start_byte()..node.end_byte())
+        }
+        MatchType::Capture(capture_name) => {
+            let capture = query
+                .capture_index_for_name(capture_name)
+                .and_then(|index| matched.captures.iter().find(|cap| cap.index == index))
+                .ok_or_else(|| ExtractionError::NamedMatchNotFound(capture_name.to_string()))?;
+            slice(capture.node.start_byte()..capture.node.end_byte())
+        }
+        MatchType::SubQuery(match_id, query_string, capture_name) => {
+            // Subqueries are compiled along with the dialect.
+            let subquery = compiled
+                .subqueries
+                .get(query_string)
+                .ok_or_else(|| ExtractionError::InvalidQuery(query_string.to_string()))?;
+            let mut cursor = QueryCursor::new();
+            let mut found = cursor
+                .matches(subquery, root_match.node, source_bytes)
+                .filter(|found| satisfies_predicates(subquery, found, source_bytes));
+            let Some(found) = found.nth(*match_id) else {
+                if *match_id > 0 {
+                    return Err(ExtractionError::NumberedMatchNotFound(*match_id));
+                }
+                let body = slice(root_match.node.start_byte()..root_match.node.end_byte())?;
+                return Err(ExtractionError::NoMatches(excerpt(&body)));
+            };
+
+            let range = match capture_name {
+                Some(capture_name) => {
+                    let capture = subquery
+                        .capture_index_for_name(capture_name)
+                        .and_then(|index| found.captures.iter().find(|cap| cap.index == index))
+                        .ok_or_else(|| {
+                            ExtractionError::NamedMatchNotFound(capture_name.to_string())
+                        })?;
+                    capture.node.start_byte()..capture.node.end_byte()
+                }
+                None => matched_outer_range(&found).ok_or(ExtractionError::NoCaptures)?,
+            };
+            slice(range)
+        }
+        MatchType::String(text) => {
+            let replaced = text.replace("${file_name}", &normalize_path(file_path));
+            Ok(Cow::from(replaced.into_bytes()))
+        }
+        MatchType::Joined(delimiter, parts) | MatchType::JoinedNormalized(delimiter, parts) => {
+            let normalize = matches!(match_type, MatchType::JoinedNormalized(..));
+
+            let mut buf = Vec::<u8>::new();
+            for (index, part) in parts.iter().enumerate() {
+                if index > 0 {
+                    buf.extend_from_slice(delimiter.as_bytes());
+                }
+                let bytes = extract(part, compiled, matched, source_bytes, file_path)?;
+                if normalize && !matches!(part, MatchType::String(_)) {
+                    buf.extend_from_slice(&normalize_whitespace(&bytes));
+                } else {
+                    buf.extend_from_slice(&bytes);
+                }
+            }
+            Ok(Cow::from(buf))
+        }
+    }
+}
+
+/// Feed the bytes that `extract` would produce to `update`, without building a
+/// buffer for joined extractions. Use this when only a hash is needed.
+pub fn checksum(
+    match_type: &MatchType,
+    compiled: &CompiledMatcher,
+    matched: &QueryMatch,
+    source_bytes: &[u8],
+    file_path: &str,
+    update: &mut dyn FnMut(&[u8]),
+) -> Result<(), ExtractionError> {
+    match match_type {
+        MatchType::Joined(delimiter, parts) | MatchType::JoinedNormalized(delimiter, parts) => {
+            let normalize = matches!(match_type, MatchType::JoinedNormalized(..));
+
+            for (index, part) in parts.iter().enumerate() {
+                if index > 0 {
+                    update(delimiter.as_bytes());
+                }
+                if normalize && !matches!(part, MatchType::String(_)) {
+                    let bytes = extract(part, compiled, matched, source_bytes, file_path)?;
+                    update(&normalize_whitespace(&bytes));
+                } else {
+                    checksum(part, compiled, matched, source_bytes, file_path, update)?;
+                }
+            }
+            Ok(())
+        }
+        _ => {
+            let bytes = extract(match_type, compiled, matched, source_bytes, file_path)?;
+            update(&bytes);
+            Ok(())
+        }
+    }
+}
+
+/// Abbreviated, single-line rendering of matched bytes for diagnostics.
+fn excerpt(bytes: &[u8]) -> String {
+    const MAX_CHARS: usize = 80;
+
+    let text = String::from_utf8_lossy(bytes);
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if text.chars().count() <= MAX_CHARS {
+        return text;
+    }
+
+    let truncated = text.chars().take(MAX_CHARS).collect::<String>();
+    format!("{truncated}...")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::Fixture;
+    use crate::lang::Matcher;
+    use std::sync::atomic::AtomicUsize;
+    use tracing_test::traced_test;
+
+    fn test_scanner() -> Scanner {
+        let mut dialects = HashMap::new();
+        dialects.insert(SupportedLanguage::Rust, Dialect::rust().unwrap());
+        dialects.insert(SupportedLanguage::Bash, Dialect::bash().unwrap());
+        Scanner::new("codebase", dialects)
+    }
+
+    fn files(paths: &[&str]) -> Vec<String> {
+        paths.iter().map(|path| path.to_string()).collect()
+    }
+
+    #[test]
+    fn scan_head_into_database() {
+        let fixture = Fixture::new();
+        let head = fixture.commit(&[
+            ("src/lib.rs", b"fn first() {}\nfn second() {}\n"),
+            ("deploy.sh", b"FOO=1\n"),
+        ]);
+
+        let mut scanner = test_scanner();
+        let found = scanner
+            .scan_revision(&fixture.repo(), head, &files(&["src/lib.rs", "deploy.sh"]))
+            .unwrap();
+        assert_eq!(found.len(), 3);
+        assert!(found.iter().all(|item| item.revision == head.to_string()));
+        assert!(found
+            .iter()
+            .all(|item| item.committed_at == Some(1_700_000_001)));
+
+        let mut conn = crate::db::connect_memory().unwrap();
+        let summary = crate::db::insert_batch(&mut conn, &found).unwrap();
+        assert_eq!(summary.inserted, 3);
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM interesting", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 3);
+    }
+
+    #[test]
+    #[traced_test]
+    fn scan_logs_within_revision_and_file_spans() {
+        let fixture = Fixture::new();
+        let head = fixture.commit(&[("src/lib.rs", b"fn first() {}\n")]);
+
+        let mut scanner = test_scanner();
+        scanner
+            .scan_revision(&fixture.repo(), head, &files(&["src/lib.rs"]))
+            .unwrap();
+
+        assert!(logs_contain(&format!(
+            "revision{{codebase=codebase revision={head}}}:file{{path=src/lib.rs}}"
+        )));
+        assert!(logs_contain("Found 1 matches"));
+    }
+
+    #[test]
+    fn results_do_not_depend_on_file_order() {
+        let fixture = Fixture::new();
+        let head = fixture.commit(&[
+            ("src/lib.rs", b"fn b() {}\nstruct A;\n"),
+            ("src/a.rs", b"fn z() {}\n"),
+            ("deploy.sh", b"FOO=1\nBAR=2\n"),
+        ]);
+        let repo = fixture.repo();
+        let forward = files(&["src/lib.rs", "src/a.rs", "deploy.sh"]);
+        let mut backward = forward.clone();
+        backward.reverse();
+
+        // Salts are random, so compare everything else.
+        let key = |found: Vec<Interesting>| {
+            found
+                .into_iter()
+                .map(|item| {
+                    (
+                        item.path,
+                        item.start_byte,
+                        item.kind,
+                        item.identifier,
+                        item.hash,
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let revision =
+            |files: &[String]| key(test_scanner().scan_revision(&repo, head, files).unwrap());
+        let worktree = |files: &[String]| key(test_scanner().scan_worktree(&fixture.dir, files));
+
+        let expected = revision(&forward);
+        let paths = expected
+            .iter()
+            .map(|(path, ..)| path.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            [
+                "deploy.sh",
+                "deploy.sh",
+                "src/a.rs",
+                "src/lib.rs",
+                "src/lib.rs"
+            ]
+        );
+        assert_eq!(revision(&backward), expected);
+        assert_eq!(worktree(&forward), expected);
+        assert_eq!(worktree(&backward), expected);
+    }
+
+    #[test]
+    fn scan_reads_commit_not_worktree() {
+        let fixture = Fixture::new();
+        let first = fixture.commit(&[("src/lib.rs", b"fn committed() {}\n")]);
+        fixture.commit(&[("src/lib.rs", b"fn later() {}\n")]);
+        std::fs::write(fixture.dir.join("src/lib.rs"), "fn uncommitted() {}\n").unwrap();
+
+        let found = test_scanner()
+            .scan_revision(
+                &fixture.repo(),
+                first,
+                &files(&["./src/lib.rs", "missing.rs"]),
+            )
+            .unwrap();
+        let identifiers = found
+            .iter()
+            .map(|item| item.identifier.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(identifiers, ["committed"]);
+        assert_eq!(found[0].path, "src/lib.rs");
+    }
+
+    #[test]
+    fn range_stops_at_merged_fork_point() {
+        let fixture = Fixture::new();
+        let root = fixture.commit(&[("lib.rs", b"fn a() {}\n")]);
+        let fork = fixture.commit(&[("lib.rs", b"fn a() { 1 }\n")]);
+        fixture.git(&["checkout", "-q", "-b", "side"]);
+        let side = fixture.commit(&[("side.rs", b"fn side() {}\n")]);
+        fixture.git(&["checkout", "-q", "-"]);
+        let from = fixture.commit(&[("lib.rs", b"fn a() { 2 }\n")]);
+        let merge = fixture.merge("side");
+
+        // The side branch is new to the range, but its fork point and the
+        // history before it aren't.
+        let repo = fixture.repo();
+        let range = revision_range(&repo, &from.to_string(), &merge.to_string()).unwrap();
+        assert_eq!(range, [side, merge]);
+        assert!(!range.contains(&fork) && !range.contains(&root));
+
+        assert!(
+            revision_range(&repo, &merge.to_string(), &merge.to_string())
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(
+            revision_range(&repo, &root.to_string(), &from.to_string()).unwrap(),
+            [fork, from]
+        );
+    }
+
+    #[test]
+    fn scan_range_records_each_revision() {
+        let fixture = Fixture::new();
+        let first = fixture.commit(&[
+            ("a.rs", b"fn unchanged() {}\n"),
+            ("b.rs", b"fn changed() { 1 }\n"),
+        ]);
+        let second = fixture.commit(&[("b.rs", b"fn changed() { 2 }\n")]);
+        let third = fixture.commit(&[("b.rs", b"fn changed() { 3 }\nfn added() {}\n")]);
+
+        let repo = fixture.repo();
+        let range = revision_range(&repo, &first.to_string(), &third.to_string()).unwrap();
+        assert_eq!(range, [second, third]);
+
+        let mut scanner = test_scanner();
+        let mut found = Vec::new();
+        for id in [first, second, third] {
+            found.extend(
+                scanner
+                    .scan_revision(&repo, id, &files(&["a.rs", "b.rs"]))
+                    .unwrap(),
+            );
+        }
+
+        let per_revision = |id: ObjectId| {
+            let mut identifiers = found
+                .iter()
+                .filter(|item| item.revision == id.to_string())
+                .map(|item| item.identifier.as_str())
+                .collect::<Vec<_>>();
+            identifiers.sort();
+            identifiers
+        };
+        assert_eq!(per_revision(first), ["changed", "unchanged"]);
+        assert_eq!(per_revision(second), ["changed", "unchanged"]);
+        assert_eq!(per_revision(third), ["added", "changed", "unchanged"]);
+
+        // a.rs is only parsed once, then reused with each revision's details.
+        assert_eq!(scanner.stats.parsed, 4);
+        assert_eq!(scanner.stats.cache_hits, 2);
+        let reused = found
+            .iter()
+            .filter(|item| item.identifier == "unchanged")
+            .map(|item| item.committed_at)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            reused,
+            [
+                Some(1_700_000_001),
+                Some(1_700_000_002),
+                Some(1_700_000_003)
+            ]
+        );
+    }
+
+    fn find_matches(dialect: &Dialect, source: &[u8]) -> Vec<Interesting> {
+        find_matches_at(dialect, "src/lib.rs", source)
+    }
+
+    /// Identifier template for whole files, as used by interesting-items.
+    const WHOLE_FILE: &str = "${file_name}";
+
+    fn subquery_matcher(kind: &str, capture: Option<&str>) -> Matcher {
+        Matcher {
+            kind: kind.to_string(),
+            query: "((function_item) @fi)".to_string(),
+            identifier: MatchType::SubQuery(
+                1,
+                "((parameter pattern: (_) @pattern type: (_) @type))".to_string(),
+                capture.map(str::to_string),
+            ),
+            contents: MatchType::Match,
+            excludes: None,
+            notes: None,
+        }
+    }
+
+    #[test]
+    fn subquery_span_or_capture() {
+        let matchers = vec![
+            subquery_matcher("span", None),
+            subquery_matcher("type", Some("type")),
+        ];
+        let dialect = Dialect::new(SupportedLanguage::Rust, matchers).unwrap();
+        assert_eq!(dialect.matchers[0].subqueries.len(), 1);
+
+        let found = find_matches(&dialect, b"fn f(a: u8, b: Vec<u8>) {}");
+        let identifiers = found
+            .iter()
+            .map(|item| (item.kind.as_str(), item.identifier.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(identifiers, [("span", "b: Vec<u8>"), ("type", "Vec<u8>")]);
+    }
+
+    #[test]
+    fn subquery_predicates_filter_matches() {
+        let typed = |predicate: &str| {
+            let subquery = format!("((parameter pattern: (_) @pattern) @p {predicate})");
+            let matcher = Matcher {
+                identifier: MatchType::SubQuery(0, subquery, Some("pattern".to_string())),
+                ..subquery_matcher("parameter", None)
+            };
+            let dialect = Dialect::new(SupportedLanguage::Rust, vec![matcher]).unwrap();
+            find_matches(&dialect, b"fn f(a: u8, b: u16, c: u32) {}")
+                .into_iter()
+                .map(|item| item.identifier)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(typed(""), ["a"]);
+        assert_eq!(typed("(#any-of? @pattern \"b\" \"c\")"), ["b"]);
+        assert_eq!(typed("(#not-any-of? @pattern \"a\" \"b\")"), ["c"]);
+        assert_eq!(typed("(#eq? @pattern \"c\")"), ["c"]);
+    }
+
+    #[test]
+    fn unsupported_subquery_predicate_rejected_by_dialect() {
+        let mut matcher = subquery_matcher("broken", None);
+        matcher.identifier = MatchType::SubQuery(
+            0,
+            "((parameter) @p (#contains? @p \"u8\"))".to_string(),
+            None,
+        );
+        let Err(e) = Dialect::new(SupportedLanguage::Rust, vec![matcher]) else {
+            panic!("Unsupported subquery predicate was accepted");
+        };
+        assert!(
+            e.to_string().contains("Unsupported predicate #contains?"),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn invalid_subquery_rejected_by_dialect() {
+        let mut matcher = subquery_matcher("broken", None);
+        matcher.identifier = MatchType::SubQuery(0, "((parameter) @p".to_string(), None);
+        let Err(e) = Dialect::new(SupportedLanguage::Rust, vec![matcher]) else {
+            panic!("Invalid subquery was accepted");
+        };
+        assert!(e.to_string().contains("broken subquery: "), "{e}");
+    }
+
+    /// Extract the first matcher's identifier from its first match.
+    fn extract_identifier(
+        dialect: &Dialect,
+        source: &[u8],
+        extracted_from: &[u8],
+    ) -> Result<Vec<u8>, ExtractionError> {
+        let compiled = &dialect.matchers[0];
+        let mut parser = Parser::new();
+        parser.set_language(dialect.grammar).unwrap();
+        let tree = parser.parse(source, None).unwrap();
+        let mut cursor = QueryCursor::new();
+        let matched = cursor
+            .matches(&compiled.query, tree.root_node(), source)
+            .next()
+            .unwrap();
+        extract(
+            &compiled.matcher.identifier,
+            compiled,
+            &matched,
+            extracted_from,
+            "src/lib.rs",
+        )
+        .map(|bytes| bytes.to_vec())
+    }
+
+    #[test]
+    fn empty_subquery_reports_body() {
+        let dialect = Dialect::new(
+            SupportedLanguage::Rust,
+            vec![subquery_matcher("span", None)],
+        )
+        .unwrap();
+        let mut matcher = subquery_matcher("first", None);
+        if let MatchType::SubQuery(index, ..) = &mut matcher.identifier {
+            *index = 0;
+        }
+        let first = Dialect::new(SupportedLanguage::Rust, vec![matcher]).unwrap();
+
+        let source = b"fn no_params() {\n    body();\n}";
+        assert_eq!(
+            extract_identifier(&first, source, source),
+            Err(ExtractionError::NoMatches(
+                "fn no_params() { body(); }".to_string()
+            ))
+        );
+        assert_eq!(
+            extract_identifier(&dialect, source, source),
+            Err(ExtractionError::NumberedMatchNotFound(1))
+        );
+    }
+
+    #[test]
+    fn extraction_error_variants() {
+        let named = |field: &str| Matcher {
+            kind: "const".to_string(),
+            query: "((const_item) @ci)".to_string(),
+            identifier: MatchType::Named(field.to_string()),
+            contents: MatchType::Match,
+            excludes: None,
+            notes: None,
+        };
+        let source = b"const A: u8 = 1;";
+
+        let dialect = Dialect::new(SupportedLanguage::Rust, vec![named("name")]).unwrap();
+        assert_eq!(
+            extract_identifier(&dialect, source, source),
+            Ok(b"A".to_vec())
+        );
+        // A tree from different source can point past the end of the bytes.
+        assert_eq!(
+            extract_identifier(&dialect, source, b"const"),
+            Err(ExtractionError::MatchBytesOutOfBounds(6..7))
+        );
+
+        let dialect = Dialect::new(SupportedLanguage::Rust, vec![named("missing")]).unwrap();
+        assert_eq!(
+            extract_identifier(&dialect, source, source),
+            Err(ExtractionError::NamedMatchNotFound("missing".to_string()))
+        );
+
+        assert!(ExtractionError::NamedMatchNotFound(String::new()).is_skippable());
+        assert!(ExtractionError::NoMatches(String::new()).is_skippable());
+        assert!(ExtractionError::NumberedMatchNotFound(1).is_skippable());
+        assert!(!ExtractionError::MatchBytesOutOfBounds(0..1).is_skippable());
+        assert!(!ExtractionError::NoCaptures.is_skippable());
+    }
+
+    #[test]
+    fn span_includes_leading_doc_comment() {
+        let documented = Matcher {
+            kind: "function".to_string(),
+            query: "(source_file (line_comment) @doc . (function_item) @outer)".to_string(),
+            identifier: MatchType::Named("name".to_string()),
+            contents: MatchType::Span,
+            excludes: None,
+            notes: None,
+        };
+        let dialect = Dialect::new(SupportedLanguage::Rust, vec![documented]).unwrap();
+        let source = b"/// Draws the widget.\nfn draw() {}\n";
+
+        let found = find_matches(&dialect, source);
+        assert_eq!(found.len(), 1);
+        let expected = Sha256::digest(b"/// Draws the widget.\nfn draw() {}");
+        assert_eq!(found[0].hash, Hash::Sha256(expected.into()));
+        // The item itself is still located by its @outer capture.
+        assert_eq!(found[0].start_byte, Some(22));
+
+        // Editing only the comment changes the hash.
+        let edited = find_matches(&dialect, b"/// Draws the gadget.\nfn draw() {}\n");
+        assert_ne!(edited[0].hash, found[0].hash);
+    }
+
+    #[test]
+    fn span_covers_captures_out_of_source_order() {
+        // Parents are captured before their children, so the last capture
+        // isn't the one that ends last.
+        let query = "(source_file
+            (attribute_item) @attr
+            .
+            (function_item name: (identifier) @name) @outer)";
+        let compiled = Matcher {
+            kind: "function".to_string(),
+            query: query.to_string(),
+            identifier: MatchType::Capture("name".to_string()),
+            contents: MatchType::Span,
+            excludes: None,
+            notes: None,
+        };
+        let dialect = Dialect::new(SupportedLanguage::Rust, vec![compiled]).unwrap();
+        let compiled = &dialect.matchers[0];
+        let source = b"#[inline]\nfn draw() { 1 }\n";
+
+        let mut parser = Parser::new();
+        parser.set_language(dialect.grammar).unwrap();
+        let tree = parser.parse(source, None).unwrap();
+        let mut cursor = QueryCursor::new();
+        let matched = cursor
+            .matches(&compiled.query, tree.root_node(), source.as_slice())
+            .next()
+            .unwrap();
+
+        // Folding the first start and last end would stop after the name.
+        let last = matched.captures.last().unwrap().node;
+        assert_eq!(last.utf8_text(source).unwrap(), "draw");
+
+        let range = matched_outer_range(&matched).unwrap();
+        assert_eq!(range, 0..25);
+        let span = extract(&MatchType::Span, compiled, &matched, source, "src/lib.rs").unwrap();
+        assert_eq!(span.as_ref(), &source[range]);
+    }
+
+    #[test]
+    fn unparseable_files_are_skipped() {
+        // Large enough that the parser checks for cancellation.
+        let garbage = b"fn (((( }}} \xff\xfe impl\n".repeat(100);
+        let valid = b"fn valid() {}\n".repeat(100);
+        let fixture = Fixture::new();
+        let head = fixture.commit(&[("garbage.rs", &garbage), ("valid.rs", &valid)]);
+        let repo = fixture.repo();
+
+        // Malformed source still produces a tree, with errors in it.
+        let mut scanner = test_scanner();
+        let found = scanner
+            .scan_revision(&repo, head, &files(&["garbage.rs", "valid.rs"]))
+            .unwrap();
+        assert_eq!(scanner.stats.parsed, 2);
+        assert!(found.iter().any(|item| item.identifier == "valid"));
+
+        // A parser that gives up produces no tree, which skips the file rather
+        // than aborting the scan.
+        static CANCELLED: AtomicUsize = AtomicUsize::new(1);
+        let mut scanner = test_scanner();
+        unsafe { scanner.parser.set_cancellation_flag(Some(&CANCELLED)) };
+        let found = scanner
+            .scan_revision(&repo, head, &files(&["garbage.rs", "valid.rs"]))
+            .unwrap();
+        assert!(found.is_empty());
+        assert_eq!(scanner.stats.unparseable, 2);
+        assert_eq!(scanner.stats.parsed, 0);
+    }
+
+    #[test]
+    fn binary_and_oversized_files_are_skipped() {
+        let oversized = format!("fn oversized() {{}}\n// {}\n", "x".repeat(100));
+        let fixture = Fixture::new();
+        let head = fixture.commit(&[
+            ("binary.rs", b"fn binary() {}\n\0\x89PNG"),
+            ("oversized.rs", oversized.as_bytes()),
+            ("small.rs", b"fn small() {}\n"),
+        ]);
+
+        let mut scanner = test_scanner();
+        scanner.max_size = 64;
+        let found = scanner
+            .scan_revision(
+                &fixture.repo(),
+                head,
+                &files(&["binary.rs", "oversized.rs", "small.rs"]),
+            )
+            .unwrap();
+
+        let identifiers = found
+            .iter()
+            .map(|item| item.identifier.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(identifiers, ["small"]);
+        assert_eq!(scanner.stats.unsupported, 2);
+        assert_eq!(scanner.stats.parsed, 1);
+    }
+
+    #[test]
+    fn stats_summarize_walk() {
+        let fixture = Fixture::new();
+        let first = fixture.commit(&[
+            ("lib.rs", b"fn a() {}\nfn b() {}\n"),
+            ("notes.txt", b"not source"),
+        ]);
+        let second = fixture.commit(&[("run.sh", b"X=1\n")]);
+
+        let repo = fixture.repo();
+        let mut scanner = test_scanner();
+        let files = files(&["lib.rs", "notes.txt", "run.sh"]);
+        for id in [first, second] {
+            scanner.scan_revision(&repo, id, &files).unwrap();
+        }
+
+        assert_eq!(
+            scanner.stats,
+            ScanStats {
+                revisions: 2,
+                parsed: 2,
+                cache_hits: 1,
+                unsupported: 2,
+                unparseable: 0,
+                missing: 1,
+                unreadable: 0,
+                matches: 5,
+                skipped: 0,
+            }
+        );
+        assert_eq!(
+            scanner.stats.to_string(),
+            "Scanned 2 revisions: 2 files parsed, 1 cache hits (33.3%), 2 unsupported, 0 unparseable, 1 missing, 0 unreadable, 5 matches, 0 skipped."
+        );
+    }
+
+    #[test]
+    fn unreadable_files_are_counted() {
+        let fixture = Fixture::new();
+        let head = fixture.commit(&[("src/lib.rs", b"fn a() {}\n")]);
+        // Directories exist but can't be read as files.
+        let files = files(&["src/lib.rs", "src", "absent.rs"]);
+
+        let mut scanner = test_scanner();
+        let found = scanner
+            .scan_revision(&fixture.repo(), head, &files)
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!((scanner.stats.missing, scanner.stats.unreadable), (1, 1));
+
+        let mut scanner = test_scanner();
+        let found = scanner.scan_worktree(&fixture.dir, &files);
+        assert_eq!(found.len(), 1);
+        assert_eq!((scanner.stats.missing, scanner.stats.unreadable), (1, 1));
+    }
+
+    #[test]
+    fn rescan_hits_persistent_cache_for_every_blob() {
+        let fixture = Fixture::new();
+        let first = fixture.commit(&[
+            ("src/lib.rs", b"struct A;\nfn b() {}\n"),
+            ("deploy.sh", b"FOO=1\n"),
+            ("notes.txt", b"Not code.\n"),
+        ]);
+        let second = fixture.commit(&[("src/lib.rs", b"fn b() {}\n")]);
+        let repo = fixture.repo();
+        let files = files(&["src/lib.rs", "deploy.sh", "notes.txt"]);
+
+        let mut scanner = test_scanner();
+        scanner.cache = Some(crate::db::connect_memory().unwrap());
+        let mut scanned = Vec::new();
+        for id in [first, second] {
+            scanned.extend(scanner.scan_revision(&repo, id, &files).unwrap());
+        }
+        assert_eq!(scanned.len(), 5);
+
+        // A fresh run over the same history reads and parses nothing.
+        let mut rescanner = test_scanner();
+        rescanner.cache = scanner.cache.take();
+        let mut rescanned = Vec::new();
+        for id in [first, second] {
+            rescanned.extend(rescanner.scan_revision(&repo, id, &files).unwrap());
+        }
+        assert_eq!(rescanned, scanned);
+        assert_eq!(rescanner.stats.cache_hits, 6);
+        assert_eq!(
+            (rescanner.stats.parsed, rescanner.stats.unsupported),
+            (0, 0)
+        );
+
+        // Changed matchers don't see matches cached by the old ones.
+        let mut changed = test_scanner();
+        changed.cache = rescanner.cache.take();
+        changed
+            .dialects
+            .get_mut(&SupportedLanguage::Rust)
+            .unwrap()
+            .disable("struct");
+        assert_ne!(changed.version(), rescanner.version());
+        let found = changed.scan_revision(&repo, first, &files).unwrap();
+        assert_eq!(found.len(), 2);
+        assert_eq!(changed.stats.cache_hits, 0);
+    }
+
+    #[test]
+    fn changed_matchers_bust_the_cache() {
+        let fixture = Fixture::new();
+        let head = fixture.commit(&[("lib.rs", b"struct A;\nfn b() {}\n")]);
+        let repo = fixture.repo();
+        let files = files(&["lib.rs"]);
+
+        let mut scanner = test_scanner();
+        assert_eq!(scanner.scan_revision(&repo, head, &files).unwrap().len(), 2);
+
+        // Editing a matcher's query changes the dialect's version.
+        let original = Dialect::rust().unwrap();
+        let mut matchers = crate::lang::matchers_rust();
+        let structs = matchers
+            .iter_mut()
+            .find(|matcher| matcher.kind == "struct")
+            .unwrap();
+        structs.query = "((struct_item name: (type_identifier) @name) @outer)".to_string();
+        let edited = Dialect::new(SupportedLanguage::Rust, matchers).unwrap();
+        assert_ne!(edited.version(), original.version());
+        assert_eq!(Dialect::rust().unwrap().version(), original.version());
+
+        // Matches found by the old matchers aren't reused.
+        let mut disabled = original;
+        disabled.disable("struct");
+        scanner.dialects.insert(SupportedLanguage::Rust, disabled);
+        let found = scanner.scan_revision(&repo, head, &files).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].kind, "function");
+        assert_eq!((scanner.stats.parsed, scanner.stats.cache_hits), (2, 0));
+
+        // Unchanged matchers still hit the cache.
+        scanner.scan_revision(&repo, head, &files).unwrap();
+        assert_eq!((scanner.stats.parsed, scanner.stats.cache_hits), (2, 1));
+    }
+
+    #[test]
+    fn resume_inserts_only_new_revisions() {
+        let fixture = Fixture::new();
+        let first = fixture.commit(&[("lib.rs", b"fn a() {}\n")]);
+        let second = fixture.commit(&[("lib.rs", b"fn a() { 2 }\n")]);
+        fixture.git(&["checkout", "-q", "-b", "side"]);
+        let side = fixture.commit(&[("lib.rs", b"fn side() {}\n")]);
+        fixture.git(&["checkout", "-q", "-"]);
+        let third = fixture.commit(&[("lib.rs", b"fn a() { 3 }\n")]);
+
+        let repo = fixture.repo();
+        let files = files(&["lib.rs"]);
+        let mut scanner = test_scanner();
+        let mut conn = crate::db::connect_memory().unwrap();
+        for id in [first, second, side] {
+            let found = scanner.scan_revision(&repo, id, &files).unwrap();
+            crate::db::insert_batch(&mut conn, &found).unwrap();
+        }
+
+        // The side branch was committed more recently, but isn't in the
+        // target's history.
+        let scanned = crate::db::scanned_revisions(&conn, "codebase").unwrap();
+        assert_eq!(scanned.len(), 3);
+        let resume = resume_point(&repo, third, &scanned).unwrap();
+        assert_eq!(resume, Some(second));
+
+        let range = revision_range(&repo, &second.to_string(), &third.to_string()).unwrap();
+        assert_eq!(range, [third]);
+        let mut found = Vec::new();
+        for id in range {
+            found.extend(scanner.scan_revision(&repo, id, &files).unwrap());
+        }
+        let summary = crate::db::insert_batch(&mut conn, &found).unwrap();
+        assert_eq!(summary.inserted, 1);
+        assert_eq!(
+            crate::db::scanned_revisions(&conn, "codebase")
+                .unwrap()
+                .len(),
+            4
+        );
+
+        // Nothing is left to scan once the target itself is stored.
+        let scanned = crate::db::scanned_revisions(&conn, "codebase").unwrap();
+        assert_eq!(resume_point(&repo, third, &scanned).unwrap(), Some(third));
+        assert!(resume_point(&repo, first, &HashSet::new())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn worktree_paths_are_root_relative() {
+        let fixture = Fixture::new();
+        fixture.commit(&[("src/lib.rs", b"fn committed() {}\n")]);
+        std::fs::write(fixture.dir.join("src/lib.rs"), "fn edited() {}\n").unwrap();
+
+        let file = crate::root_relative_path(
+            &fixture.dir,
+            &fixture.dir.join("src"),
+            Path::new("./lib.rs"),
+        )
+        .unwrap();
+        assert_eq!(file, "src/lib.rs");
+
+        let found = test_scanner().scan_worktree(&fixture.dir, &[file]);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].identifier, "edited");
+        assert_eq!(found[0].path, "src/lib.rs");
+    }
+
+    #[test]
+    fn non_utf8_identifiers_are_skipped() {
+        // Identifiers can't contain invalid UTF-8, but string values can.
+        let matcher = Matcher {
+            kind: "const".to_string(),
+            query: "((const_item value: (string_literal) @value) @outer)".to_string(),
+            identifier: MatchType::Capture("value".to_string()),
+            contents: MatchType::Match,
+            excludes: None,
+            notes: None,
+        };
+        let dialect = Dialect::new(SupportedLanguage::Rust, vec![matcher]).unwrap();
+
+        let found = find_matches(
+            &dialect,
+            b"const A: &str = \"caf\xe9\";\nconst B: &str = \"cafe\";\n",
+        );
+        let identifiers = found
+            .iter()
+            .map(|item| item.identifier.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(identifiers, ["\"cafe\""]);
+    }
+
+    #[test]
+    #[traced_test]
+    fn non_utf8_identifiers_are_logged_as_warnings() {
+        let matcher = Matcher {
+            kind: "value".to_string(),
+            query: "((const_item value: (string_literal) @value) @outer)".to_string(),
+            identifier: MatchType::Capture("value".to_string()),
+            contents: MatchType::Match,
+            excludes: None,
+            notes: None,
+        };
+        let dialect = Dialect::new(SupportedLanguage::Rust, vec![matcher]).unwrap();
+        let fixture = Fixture::new();
+        let head = fixture.commit(&[("src/lib.rs", b"const A: &str = \"caf\xe9\";\n")]);
+
+        let mut scanner = Scanner::new(
+            "codebase",
+            HashMap::from([(SupportedLanguage::Rust, dialect)]),
+        );
+        scanner
+            .scan_revision(&fixture.repo(), head, &files(&["src/lib.rs"]))
+            .unwrap();
+
+        logs_assert(|lines| {
+            lines
+                .iter()
+                .any(|line| {
+                    line.contains("WARN")
+                        && line.contains(
+                            "Skipping value in src/lib.rs: Identifier is not valid UTF-8: \"caf",
+                        )
+                })
+                .then_some(())
+                .ok_or_else(|| format!("No warning in {lines:?}"))
+        });
+    }
+
+    #[test]
+    fn file_matches_record_context_and_skips() {
+        let named = |kind: &str, field: &str| Matcher {
+            kind: kind.to_string(),
+            query: "((const_item) @ci)".to_string(),
+            identifier: MatchType::Named(field.to_string()),
+            contents: MatchType::Match,
+            excludes: None,
+            notes: None,
+        };
+        let invalid = Matcher {
+            kind: "value".to_string(),
+            query: "((const_item value: (string_literal) @value) @outer)".to_string(),
+            identifier: MatchType::Capture("value".to_string()),
+            contents: MatchType::Match,
+            excludes: None,
+            notes: None,
+        };
+        let matchers = vec![named("const", "name"), named("typed", "missing"), invalid];
+        let dialect = Dialect::new(SupportedLanguage::Rust, matchers).unwrap();
+        let source = b"const A: &str = \"caf\xe9\";\n";
+
+        let mut parser = Parser::new();
+        let context = ScanContext {
+            codebase: "upstream",
+            revision: "abc123",
+            path: Path::new("./src//lib.rs"),
+        };
+        let found = find_matches_in_file(&mut parser, &context, source, &dialect).unwrap();
+
+        let [item] = found.items.as_slice() else {
+            panic!("Expected a single item: {:?}", found.items);
+        };
+        assert_eq!(item.codebase, "upstream");
+        assert_eq!(item.revision, "abc123");
+        assert_eq!(item.path, "src/lib.rs");
+        assert_eq!(item.identifier, "A");
+        assert_eq!(
+            found.skipped,
+            [
+                (
+                    "typed".to_string(),
+                    Skipped::Identifier(ExtractionError::NamedMatchNotFound("missing".to_string()))
+                ),
+                (
+                    "value".to_string(),
+                    Skipped::InvalidIdentifier("\"caf\u{fffd}\"".to_string())
+                ),
+            ]
+        );
+
+        // Scans count skipped matches rather than reporting each one.
+        let mut scanner = Scanner::new("upstream", HashMap::new());
+        scanner.dialects.insert(SupportedLanguage::Rust, dialect);
+        let fixture = Fixture::new();
+        let head = fixture.commit(&[("src/lib.rs", source)]);
+        let found = scanner
+            .scan_revision(&fixture.repo(), head, &files(&["src/lib.rs"]))
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!((scanner.stats.matches, scanner.stats.skipped), (1, 2));
+    }
+
+    #[test]
+    fn whole_file_identifier_and_hash() {
+        let dialect = Dialect::rust()
+            .unwrap()
+            .with_whole_file("${file_name}")
+            .unwrap();
+        let source = b"fn a() {}\n\nstruct B;\n";
+
+        let found = find_matches(&dialect, source);
+        let files = found
+            .iter()
+            .filter(|item| item.kind == "file")
+            .collect::<Vec<_>>();
+        let [file] = files.as_slice() else {
+            panic!("Expected a single file match: {found:?}");
+        };
+        // Identified by name, not by contents.
+        assert_eq!(file.identifier, "src/lib.rs");
+        assert_eq!(file.hash, Hash::Sha256(Sha256::digest(source).into()));
+        assert_eq!(
+            (file.start_byte, file.length),
+            (Some(0), Some(source.len()))
+        );
+        // Other matchers still run alongside.
+        assert_eq!(found.len(), 3);
+    }
+
+    #[test]
+    fn whitespace_collapses_across_chunks() {
+        let source = b"  fn  draw(\n\tx: u8 )\n{ }\n\n";
+        for size in 1..source.len() {
+            let mut collapser = WhitespaceCollapser::default();
+            let mut collapsed = Vec::new();
+            for chunk in source.chunks(size) {
+                collapser.feed(chunk, &mut |run| collapsed.extend_from_slice(run));
+            }
+            assert_eq!(collapsed, normalize_whitespace(source), "chunk size {size}");
+        }
+    }
+
+    #[test]
+    fn normalized_hash_ignores_whitespace() {
+        let dialect = Dialect::rust().unwrap();
+        let [original, reindented, edited] = [
+            &b"fn draw(x: u8) { x }\n"[..],
+            b"fn  draw(x: u8)\n{\n    x\n}\n",
+            b"fn draw(x: u16) { x }\n",
+        ]
+        .map(|source| find_matches(&dialect, source).remove(0));
+
+        assert_ne!(original.hash, reindented.hash);
+        assert_eq!(original.normalized_hash, reindented.normalized_hash);
+        assert_ne!(original.normalized_hash, edited.normalized_hash);
+    }
+
+    #[test]
+    fn file_identifier_is_canonical_path() {
+        let sources = [
+            (
+                Dialect::rust().unwrap(),
+                "./src//nested/../lib.rs",
+                "fn a() {}\n",
+            ),
+            (Dialect::bash().unwrap(), "scripts\\deploy.sh", "FOO=1\n"),
+        ];
+        let expected = ["src/lib.rs", "scripts/deploy.sh"];
+
+        for ((dialect, path, source), expected) in sources.into_iter().zip(expected) {
+            let dialect = dialect.with_whole_file(WHOLE_FILE).unwrap();
+            let found = find_matches_at(&dialect, path, source.as_bytes());
+            let file = found.iter().find(|item| item.kind == "file").unwrap();
+            assert_eq!(file.identifier, expected);
+            assert_eq!(file.path, expected);
+        }
+    }
+
+    #[test]
+    fn transcode_maps_offsets() {
+        // Shift_JIS encodes あ in two bytes, and UTF-8 in three.
+        let transcoded = transcode(encoding_rs::SHIFT_JIS, b"\x82\xa0a");
+        assert_eq!(transcoded.text, "\u{3042}a".as_bytes());
+        assert_eq!(transcoded.offsets, [0, 0, 0, 2, 3]);
+        assert_eq!(transcoded.original_range(0..3), 0..2);
+        assert_eq!(transcoded.original_range(3..4), 2..3);
+    }
+
+    #[test]
+    fn scan_latin1_source() {
+        let dialect = Dialect::rust()
+            .unwrap()
+            .with_encoding(Encoding::for_label(b"latin1"));
+        let source = b"// \xe9t\xe9\nfn caf\xe9() {}\n";
+        let found = find_matches(&dialect, source);
+
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].identifier, "caf\u{e9}");
+        // Offsets are of the Latin-1 bytes, where each character is one byte.
+        assert_eq!(found[0].start_byte, Some(7));
+        assert_eq!(found[0].length, Some(12));
+        assert_ne!(dialect.version(), Dialect::rust().unwrap().version());
+    }
+}
@@ -0,0 +1,43 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use rawr::fixture::Fixture;
+
+const INTERESTING_ITEMS: &str = env!("CARGO_BIN_EXE_interesting-items");
+const EXPORT: &str = env!("CARGO_BIN_EXE_export");
+
+#[test]
+fn export_filters_and_reparses() {
+    let fixture = Fixture::new();
+    let head = fixture.commit(&[("src/lib.rs", b"fn first() {}\nstruct Shape;\n")]);
+    let database = fixture.path("rawr.db");
+    let database_arg = database.to_str().unwrap();
+    fixture.run(
+        INTERESTING_ITEMS,
+        &[
+            "--database",
+            database_arg,
+            "--revision",
+            "HEAD",
+            "src/lib.rs",
+        ],
+    );
+
+    let output = fixture.run(EXPORT, &["--kind", "struct", database_arg]);
+    let rows = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    let [row] = rows.as_slice() else {
+        panic!("Expected one struct: {output}");
+    };
+    assert_eq!(row["identifier"], "Shape");
+    assert_eq!(row["revision"], head.to_string());
+    let hash = row["hash"].as_str().unwrap();
+    assert!(rawr::Hash::from_hex("sha256", hash).is_ok(), "{hash}");
+
+    let output = fixture.run(EXPORT, &["--format", "csv", database_arg]);
+    let lines = output.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 3, "{output}");
+    assert!(lines[0].starts_with("codebase,revision,"), "{output}");
+    assert!(lines[1].contains(",function,first,sha256,"), "{output}");
+}
@@ -0,0 +1,69 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use rawr::fixture::{row_count, Fixture};
+
+const INTERESTING_ITEMS: &str = env!("CARGO_BIN_EXE_interesting-items");
+
+#[test]
+fn dry_run_leaves_database_unchanged() {
+    let fixture = Fixture::new();
+    fixture.commit(&[("src/lib.rs", b"fn first() {}\n")]);
+    let database = fixture.path("rawr.db");
+    let database_arg = database.to_str().unwrap();
+
+    // A dry run doesn't create a missing database.
+    let output = fixture.run(
+        INTERESTING_ITEMS,
+        &[
+            "--database",
+            database_arg,
+            "--revision",
+            "HEAD",
+            "--dry-run",
+            "src/lib.rs",
+        ],
+    );
+    assert!(output.contains("Would store function first"), "{output}");
+    assert!(!database.exists());
+
+    fixture.run(
+        INTERESTING_ITEMS,
+        &[
+            "--database",
+            database_arg,
+            "--revision",
+            "HEAD",
+            "src/lib.rs",
+        ],
+    );
+    assert_eq!(row_count(&database), 1);
+
+    // New matches that would otherwise be inserted are only reported.
+    fixture.commit(&[("src/lib.rs", b"fn first() {}\nfn second() {}\n")]);
+    let output = fixture.run(
+        INTERESTING_ITEMS,
+        &[
+            "--database",
+            database_arg,
+            "--revision",
+            "HEAD",
+            "--dry-run",
+            "src/lib.rs",
+        ],
+    );
+    assert!(output.contains("Would store function second"), "{output}");
+    assert!(output.contains("Dry run. Nothing was written."), "{output}");
+    assert_eq!(row_count(&database), 1);
+
+    fixture.run(
+        INTERESTING_ITEMS,
+        &[
+            "--database",
+            database_arg,
+            "--revision",
+            "HEAD",
+            "src/lib.rs",
+        ],
+    );
+    assert_eq!(row_count(&database), 3);
+}
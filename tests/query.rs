@@ -0,0 +1,44 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use rawr::fixture::Fixture;
+
+const QUERY: &str = env!("CARGO_BIN_EXE_query");
+
+#[test]
+fn query_prints_captures() {
+    let fixture = Fixture::new();
+    fixture.commit(&[("src/lib.rs", b"fn first() {}\nfn second() {}\n")]);
+    let query = "(function_item name: (identifier) @name)";
+
+    let output = fixture.run(QUERY, &[query, "src/lib.rs"]);
+    assert!(
+        output.contains("@name identifier 3..8: \"first\""),
+        "{output}"
+    );
+    assert!(
+        output.contains("@name identifier 17..23: \"second\""),
+        "{output}"
+    );
+    assert!(output.ends_with("2 matches.\n"), "{output}");
+
+    // Revisions are read from history rather than the working directory.
+    fixture.commit(&[("src/lib.rs", b"fn third() {}\n")]);
+    let output = fixture.run(QUERY, &["--revision", "HEAD~1", query, "src/lib.rs"]);
+    assert!(output.contains("\"second\""), "{output}");
+    assert!(!output.contains("\"third\""), "{output}");
+
+    let invalid = fixture.run_output(QUERY, &["(function_item", "src/lib.rs"]);
+    assert!(!invalid.status.success());
+}
+
+#[test]
+fn query_rejects_directory_at_revision() {
+    let fixture = Fixture::new();
+    fixture.commit(&[("src/lib.rs", b"fn first() {}\n")]);
+
+    let output = fixture.run_output(QUERY, &["--revision", "HEAD", "(identifier) @id", "src"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("src is not a file"), "{stderr}");
+    assert!(!stderr.contains("panicked"), "{stderr}");
+}
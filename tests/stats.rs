@@ -0,0 +1,58 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use rawr::fixture::{row_count, Fixture};
+
+const INTERESTING_ITEMS: &str = env!("CARGO_BIN_EXE_interesting-items");
+const STATS: &str = env!("CARGO_BIN_EXE_stats");
+
+#[test]
+fn stats_summarize_database() {
+    let fixture = Fixture::new();
+    let first = fixture.commit(&[
+        ("src/lib.rs", b"fn first() {}\nstruct Shape;\n"),
+        ("deploy.sh", b"FOO=1\n"),
+    ]);
+    let second = fixture.commit(&[("src/lib.rs", b"fn first() {}\n")]);
+    let database = fixture.path("rawr.db");
+    let database_arg = database.to_str().unwrap();
+    let first_arg = first.to_string();
+    fixture.run(
+        INTERESTING_ITEMS,
+        &[
+            "--database",
+            database_arg,
+            "--revision",
+            &first_arg,
+            "src/lib.rs",
+            "deploy.sh",
+        ],
+    );
+    // Continues after the first revision.
+    fixture.run(
+        INTERESTING_ITEMS,
+        &[
+            "--database",
+            database_arg,
+            "--revision",
+            "HEAD",
+            "src/lib.rs",
+        ],
+    );
+    assert_eq!(row_count(&database), 4);
+
+    let output = fixture.run(STATS, &["--format", "json", database_arg]);
+    let stats: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(
+        stats,
+        serde_json::json!({
+            "rows": 4,
+            "codebases": { "(self)": 4 },
+            "revisions": { first_arg: 3, second.to_string(): 1 },
+            "kinds": { "function": 2, "struct": 1, "Variable": 1 },
+        })
+    );
+
+    let text = fixture.run(STATS, &[database_arg]);
+    assert!(text.starts_with("4 matches.\n"), "{text}");
+    assert!(text.contains("\tstruct: 1\n"), "{text}");
+}